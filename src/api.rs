@@ -0,0 +1,350 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::extract::{Path, Query, State};
+use axum::http::header::AUTHORIZATION;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use twilight_model::id::marker::UserMarker;
+use twilight_model::id::Id;
+use url::Url;
+
+use crate::errors::MieError;
+use crate::media::MediaOrigin;
+use crate::metrics::{job_finished, Outcome};
+use crate::retry::Backoff;
+use crate::stage::{mirror_link, Progress, Stage};
+use crate::AppContext;
+
+// Finished jobs are forgotten once there are more than this many
+const MAX_JOBS: usize = 256;
+// Jobs a token's user can have going at once, the rest get a 429
+const MAX_RUNNING: usize = 4;
+const DEFAULT_MEDIA_LIMIT: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum JobStatus {
+    Probing,
+    Downloading,
+    Uploading,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Job {
+    id: String,
+    url: String,
+    status: JobStatus,
+    links: Vec<String>,
+    error: Option<String>,
+    #[serde(skip)]
+    owner: Id<UserMarker>,
+    // Unix seconds
+    created_at: u64,
+}
+
+impl Job {
+    fn is_finished(&self) -> bool {
+        matches!(self.status, JobStatus::Done | JobStatus::Failed)
+    }
+}
+
+struct ApiState {
+    ctx: Arc<AppContext>,
+    jobs: Mutex<HashMap<String, Job>>,
+}
+
+impl ApiState {
+    fn update(&self, id: &str, update: impl FnOnce(&mut Job)) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(id) {
+            update(job);
+        }
+    }
+
+    /// The job, as long as it belongs to whoever is asking.
+    fn job(&self, id: &str, owner: Id<UserMarker>) -> Option<Job> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .get(id)
+            .filter(|job| job.owner == owner)
+            .cloned()
+    }
+
+    /// Resolves the bearer token to the user its jobs run as.
+    fn authenticate(&self, headers: &HeaderMap) -> Option<Id<UserMarker>> {
+        let token = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))?;
+
+        // Constant time so the response doesn't hint at how much matched
+        self.ctx
+            .config
+            .api_tokens
+            .iter()
+            .find(|(api_token, _)| {
+                api_token.len() == token.len()
+                    && openssl::memcmp::eq(api_token.as_bytes(), token.as_bytes())
+            })
+            .map(|(_, owner)| *owner)
+    }
+}
+
+/// Lets scripts and share sheets mirror links without going through discord.
+/// Every request needs one of the configured tokens, there are none by default.
+pub fn router<S>(ctx: Arc<AppContext>) -> Router<S> {
+    let state = Arc::new(ApiState {
+        ctx,
+        jobs: Mutex::default(),
+    });
+
+    Router::new()
+        .route("/jobs", post(submit_job))
+        .route("/jobs/{id}", get(job_status))
+        .route("/jobs/{id}/result", get(job_result))
+        .route("/media", get(recent_media))
+        .with_state(state)
+}
+
+#[derive(Debug, Deserialize)]
+struct SubmitJob {
+    url: String,
+}
+
+async fn submit_job(
+    State(state): State<Arc<ApiState>>,
+    headers: HeaderMap,
+    Json(body): Json<SubmitJob>,
+) -> Response {
+    let Some(owner) = state.authenticate(&headers) else {
+        return error(StatusCode::UNAUTHORIZED, "Missing or unknown api token");
+    };
+
+    let url = match Url::parse(&body.url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => url,
+        _ => return error(StatusCode::BAD_REQUEST, "Not a http link"),
+    };
+
+    let id: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(12)
+        .map(char::from)
+        .collect();
+    let job = Job {
+        id: id.clone(),
+        url: url.to_string(),
+        status: JobStatus::Probing,
+        links: vec![],
+        error: None,
+        owner,
+        created_at: now(),
+    };
+
+    {
+        let mut jobs = state.jobs.lock().unwrap();
+        let running = jobs
+            .values()
+            .filter(|job| job.owner == owner && !job.is_finished())
+            .count();
+        if running >= MAX_RUNNING {
+            return error(
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many jobs running, wait for one to finish",
+            );
+        }
+
+        forget_finished(&mut jobs);
+        jobs.insert(id.clone(), job.clone());
+    }
+
+    tracing::info!(%url, id, %owner, "job submitted over the api");
+    tokio::spawn(run_job(state.clone(), id, url, owner));
+
+    (StatusCode::ACCEPTED, Json(job)).into_response()
+}
+
+async fn job_status(
+    State(state): State<Arc<ApiState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(owner) = state.authenticate(&headers) else {
+        return error(StatusCode::UNAUTHORIZED, "Missing or unknown api token");
+    };
+
+    match state.job(&id, owner) {
+        Some(job) => Json(job).into_response(),
+        None => error(StatusCode::NOT_FOUND, "No such job"),
+    }
+}
+
+/// Redirects to the first link once the job is done, so a shortcut can open
+/// it straight away. Jobs still running answer with their status.
+async fn job_result(
+    State(state): State<Arc<ApiState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(owner) = state.authenticate(&headers) else {
+        return error(StatusCode::UNAUTHORIZED, "Missing or unknown api token");
+    };
+
+    let Some(job) = state.job(&id, owner) else {
+        return error(StatusCode::NOT_FOUND, "No such job");
+    };
+
+    match job.status {
+        JobStatus::Done => match job.links.first() {
+            Some(link) => Redirect::to(link).into_response(),
+            None => Json(job).into_response(),
+        },
+        JobStatus::Failed => (StatusCode::UNPROCESSABLE_ENTITY, Json(job)).into_response(),
+        _ => (StatusCode::ACCEPTED, Json(job)).into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct MediaQuery {
+    limit: Option<usize>,
+}
+
+/// Uploads made by the token's user, newest first.
+async fn recent_media(
+    State(state): State<Arc<ApiState>>,
+    Query(query): Query<MediaQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(owner) = state.authenticate(&headers) else {
+        return error(StatusCode::UNAUTHORIZED, "Missing or unknown api token");
+    };
+
+    let mut records = match state.ctx.media.all() {
+        Ok(records) => records,
+        Err(err) => {
+            tracing::error!("failed to read media store: {}", err);
+            return error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to read media");
+        }
+    };
+
+    records.retain(|record| record.uploader_id == owner);
+    records.sort_by_key(|record| std::cmp::Reverse(record.created_at));
+
+    let media = records
+        .iter()
+        .take(query.limit.unwrap_or(DEFAULT_MEDIA_LIMIT))
+        .map(|record| {
+            json!({
+                "url": record.url,
+                "file_name": record.file_name,
+                "size": record.size,
+                "source_url": record.source_url,
+                "title": record.title,
+                "created_at": record.created_at,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    Json(media).into_response()
+}
+
+async fn run_job(state: Arc<ApiState>, id: String, url: Url, owner: Id<UserMarker>) {
+    let result = mirror(&state, &id, &url, owner).await;
+
+    state.update(&id, |job| match result {
+        Ok(links) => {
+            job.status = JobStatus::Done;
+            job.links = links;
+        }
+        Err(err) => {
+            tracing::warn!(%url, id, "api job failed: {}", err);
+            let outcome = err
+                .downcast_ref::<MieError>()
+                .map(Outcome::from)
+                .unwrap_or(Outcome::Failed);
+            job_finished(url.as_str(), outcome);
+            job.status = JobStatus::Failed;
+            job.error = Some(err.to_string());
+        }
+    });
+}
+
+/// Same stages a link posted in discord goes through, minus the crop.
+async fn mirror(
+    state: &ApiState,
+    id: &str,
+    video_url: &Url,
+    owner: Id<UserMarker>,
+) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    let origin = MediaOrigin {
+        guild_id: None,
+        channel_id: None,
+        message_id: None,
+        uploader_id: owner,
+    };
+    let mirrored = mirror_link(
+        &state.ctx,
+        video_url,
+        &origin,
+        &mut JobProgress { state, id },
+    )
+    .await?;
+    Ok(mirrored.links)
+}
+
+/// Keeps the job's status in step with the stage it's in.
+struct JobProgress<'a> {
+    state: &'a ApiState,
+    id: &'a str,
+}
+
+impl Progress for JobProgress<'_> {
+    async fn started(&mut self, stage: Stage) {
+        let status = match stage {
+            Stage::Probe => JobStatus::Probing,
+            Stage::Download | Stage::Transcode => JobStatus::Downloading,
+            Stage::Upload => JobStatus::Uploading,
+        };
+        self.state.update(self.id, |job| job.status = status);
+    }
+
+    async fn retrying(&mut self, _: Stage, _: &Backoff) {}
+}
+
+fn forget_finished(jobs: &mut HashMap<String, Job>) {
+    if jobs.len() < MAX_JOBS {
+        return;
+    }
+
+    let mut finished = jobs
+        .values()
+        .filter(|job| job.is_finished())
+        .map(|job| (job.created_at, job.id.clone()))
+        .collect::<Vec<_>>();
+    finished.sort();
+
+    for (_, id) in finished.into_iter().take(jobs.len() + 1 - MAX_JOBS) {
+        jobs.remove(&id);
+    }
+}
+
+fn error(status: StatusCode, message: &str) -> Response {
+    (status, Json(json!({ "error": message }))).into_response()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
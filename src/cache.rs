@@ -0,0 +1,502 @@
+use std::error::Error;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use backblaze_b2_client::simple_client::B2SimpleClient;
+use redb::{Database, TableDefinition};
+use serde::{Deserialize, Serialize};
+use twilight_model::channel::message::embed::EmbedField;
+use twilight_model::id::marker::{GuildMarker, UserMarker};
+use twilight_model::id::Id;
+use url::Url;
+
+use crate::embed::MieEmbed;
+use crate::env::Config;
+use crate::links::{links_expire, public_link};
+use crate::proxy::{http_client, proxy_chain};
+
+type DynamicResult<T = ()> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Scoped, normalized url to a json encoded CachedResult
+const RESULTS: TableDefinition<&str, &[u8]> = TableDefinition::new("results");
+
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Hosts that only redirect to the real post
+const SHORT_LINK_HOSTS: &[&str] = &[
+    "vm.tiktok.com",
+    "vt.tiktok.com",
+    "t.co",
+    "bit.ly",
+    "redd.it",
+    "pin.it",
+];
+
+const TRACKING_PARAMS: &[&str] = &[
+    "fbclid",
+    "gclid",
+    "igshid",
+    "igsh",
+    "si",
+    "ref",
+    "ref_src",
+    "ref_url",
+    "share_id",
+    "mibextid",
+    "is_from_webapp",
+    "sender_device",
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedResult {
+    pub links: Vec<String>,
+    // Bucket keys behind the links, in the same order
+    #[serde(default)]
+    pub files: Vec<String>,
+    // Links that are images, shown in the embed for galleries
+    pub images: Vec<String>,
+    pub is_gallery: bool,
+    pub cropped: Option<String>,
+    #[serde(default)]
+    pub cropped_file: Option<String>,
+    pub download_time: u128,
+    pub upload_time: u128,
+    // Unix seconds
+    pub created_at: u64,
+}
+
+impl CachedResult {
+    pub fn new(
+        links: Vec<String>,
+        files: Vec<String>,
+        images: Vec<String>,
+        is_gallery: bool,
+        download_time: u128,
+        upload_time: u128,
+    ) -> Self {
+        CachedResult {
+            links,
+            files,
+            images,
+            is_gallery,
+            cropped: None,
+            cropped_file: None,
+            download_time,
+            upload_time,
+            created_at: now(),
+        }
+    }
+}
+
+impl CachedResult {
+    /// Every file the links point to, the crop included.
+    pub fn all_files(&self) -> Vec<String> {
+        self.files
+            .iter()
+            .chain(&self.cropped_file)
+            .cloned()
+            .collect()
+    }
+
+    /// Swaps the links for freshly signed ones when they expire, the ones
+    /// from last time might be about to stop working.
+    async fn resign(&mut self, config: &Config, b2: &B2SimpleClient) -> DynamicResult {
+        if !links_expire(config) {
+            return Ok(());
+        }
+
+        // Entries from before file names were kept just run out
+        if self.files.len() != self.links.len() {
+            return Ok(());
+        }
+
+        for (file_name, link) in self.files.iter().zip(&mut self.links) {
+            let fresh = public_link(config, b2, file_name).await?;
+            for image in self.images.iter_mut().filter(|image| *image == link) {
+                *image = fresh.clone();
+            }
+            *link = fresh;
+        }
+
+        if let (Some(file_name), Some(cropped)) = (&self.cropped_file, &mut self.cropped) {
+            *cropped = public_link(config, b2, file_name).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Previous download results, kept in a local file so the same post being
+/// shared around doesn't get downloaded and uploaded again. Failing to open
+/// the file only disables the cache.
+pub struct ResultCache {
+    db: Option<Database>,
+    ttl: u64,
+}
+
+impl ResultCache {
+    pub fn open(config: &Config) -> Self {
+        let db = match Database::create(&config.cache_path) {
+            Ok(db) => Some(db),
+            Err(err) => {
+                tracing::warn!(
+                    config.cache_path,
+                    "failed to open cache, disabling it: {}",
+                    err
+                );
+                None
+            }
+        };
+
+        // Signed links stop working, so don't hand them out after that
+        let ttl = match links_expire(config) {
+            true => config.cache_ttl.min(config.link_lifetime),
+            false => config.cache_ttl,
+        };
+
+        ResultCache { db, ttl }
+    }
+
+    pub fn get(&self, key: &str) -> Option<CachedResult> {
+        let db = self.db.as_ref()?;
+
+        match read_result(db, key) {
+            Ok(cached) => cached.filter(|cached| now() < cached.created_at + self.ttl),
+            Err(err) => {
+                tracing::warn!(key, "failed to read from cache: {}", err);
+                None
+            }
+        }
+    }
+
+    /// The cached result with links that are good for the full lifetime,
+    /// treated as missing when they can't be signed again.
+    pub async fn get_signed(
+        &self,
+        key: &str,
+        config: &Config,
+        b2: &B2SimpleClient,
+    ) -> Option<CachedResult> {
+        let mut cached = self.get(key)?;
+        match cached.resign(config, b2).await {
+            Ok(()) => Some(cached),
+            Err(err) => {
+                tracing::warn!(key, "failed to sign cached links again: {}", err);
+                None
+            }
+        }
+    }
+
+    pub fn insert(&self, key: &str, cached: &CachedResult) {
+        let Some(db) = &self.db else {
+            return;
+        };
+
+        if let Err(err) = write_result(db, key, cached) {
+            tracing::warn!(key, "failed to write to cache: {}", err);
+        }
+    }
+
+    pub fn remove(&self, key: &str) {
+        let Some(db) = &self.db else {
+            return;
+        };
+
+        if let Err(err) = remove_result(db, key) {
+            tracing::warn!(key, "failed to remove from cache: {}", err);
+        }
+    }
+}
+
+fn read_result(db: &Database, key: &str) -> anyhow::Result<Option<CachedResult>> {
+    let read = db.begin_read()?;
+    let table = match read.open_table(RESULTS) {
+        Ok(table) => table,
+        // Nothing has been cached yet
+        Err(redb::TableError::TableDoesNotExist(_)) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    match table.get(key)? {
+        Some(value) => Ok(Some(serde_json::from_slice(value.value())?)),
+        None => Ok(None),
+    }
+}
+
+fn write_result(db: &Database, key: &str, cached: &CachedResult) -> anyhow::Result<()> {
+    let value = serde_json::to_vec(cached)?;
+    let write = db.begin_write()?;
+    write.open_table(RESULTS)?.insert(key, value.as_slice())?;
+    write.commit()?;
+    Ok(())
+}
+
+fn remove_result(db: &Database, key: &str) -> anyhow::Result<()> {
+    let write = db.begin_write()?;
+    write.open_table(RESULTS)?.remove(key)?;
+    write.commit()?;
+    Ok(())
+}
+
+/// Turns the url into a key that's the same for every way of linking the
+/// same post. Short links are followed, known sites are reduced to their
+/// post id and everything else loses its tracking params.
+pub async fn normalize_url(config: &Config, url: &Url) -> String {
+    let url = match is_short_link(url) {
+        true => resolve_short_link(config, url)
+            .await
+            .unwrap_or_else(|| url.clone()),
+        false => url.clone(),
+    };
+
+    canonical_id(&url).unwrap_or_else(|| strip_tracking(&url))
+}
+
+/// Key for the url shared by someone in the guild. Each guild keeps its own
+/// results, uploads count against its quota and get cleaned up on its own
+/// schedule. Links sent outside of a guild are kept per user instead.
+pub async fn cache_key(
+    config: &Config,
+    url: &Url,
+    guild_id: Option<Id<GuildMarker>>,
+    uploader_id: Id<UserMarker>,
+) -> String {
+    let scope = match guild_id {
+        Some(guild_id) => format!("guild:{}", guild_id),
+        None => format!("user:{}", uploader_id),
+    };
+    format!("{}:{}", scope, normalize_url(config, url).await)
+}
+
+/// Fills in the embed with a previous result, same as a fresh download
+/// would have ended up looking.
+pub fn show_cached<'a>(embed: &'a mut MieEmbed, cached: &CachedResult) -> &'a mut MieEmbed {
+    // Every file of a gallery can have failed to upload
+    let first = cached.links.first();
+    if cached.is_gallery {
+        embed
+            .title(format!("Downloaded {} files", cached.links.len()))
+            .description(cached.links.join("\n"));
+        if let Some(first) = first {
+            embed.images(first.clone(), cached.images.clone());
+        }
+    } else {
+        embed.title(format!("Download: {}", first.map_or("", String::as_str)));
+
+        if let Some(cropped) = &cached.cropped {
+            embed.description(format!("Cropped: {}", cropped));
+        }
+    }
+
+    embed
+        .add_field(EmbedField {
+            name: "Download".to_string(),
+            value: format!("{}ms", cached.download_time),
+            inline: true,
+        })
+        .add_field(EmbedField {
+            name: "Upload".to_string(),
+            value: format!("{}ms", cached.upload_time),
+            inline: true,
+        })
+        .add_field(EmbedField {
+            name: "Cached".to_string(),
+            value: format!("<t:{}:R>", cached.created_at),
+            inline: true,
+        })
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn host(url: &Url) -> String {
+    let host = url.host_str().unwrap_or_default().to_lowercase();
+    ["www.", "m.", "mobile."]
+        .iter()
+        .find_map(|prefix| host.strip_prefix(prefix))
+        .map(str::to_string)
+        .unwrap_or(host)
+}
+
+fn is_short_link(url: &Url) -> bool {
+    let host = host(url);
+    SHORT_LINK_HOSTS.contains(&host.as_str())
+        || (host == "tiktok.com" && url.path().starts_with("/t/"))
+}
+
+async fn resolve_short_link(config: &Config, url: &Url) -> Option<Url> {
+    let proxy = proxy_chain(config, url.as_str()).swap_remove(0);
+    let client = http_client(config, proxy.as_deref()).ok()?;
+
+    // Redirects are followed by default, the final url is the post itself
+    let response = client
+        .head(url.clone())
+        .timeout(RESOLVE_TIMEOUT)
+        .send()
+        .await
+        .ok()?;
+
+    tracing::debug!(
+        url = url.as_str(),
+        resolved = response.url().as_str(),
+        "resolved short link"
+    );
+    Some(response.url().clone())
+}
+
+fn canonical_id(url: &Url) -> Option<String> {
+    let host = host(url);
+    let segments = url
+        .path_segments()?
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>();
+    let after = |name: &str| {
+        segments
+            .iter()
+            .position(|segment| *segment == name)
+            .and_then(|index| segments.get(index + 1))
+            .map(|id| id.to_string())
+    };
+
+    let (site, id) = match host.as_str() {
+        "youtube.com" | "music.youtube.com" => (
+            "youtube",
+            url.query_pairs()
+                .find(|(key, _)| key == "v")
+                .map(|(_, id)| id.to_string())
+                .or_else(|| after("shorts"))
+                .or_else(|| after("live"))
+                .or_else(|| after("embed"))?,
+        ),
+        "youtu.be" => ("youtube", segments.first()?.to_string()),
+        "tiktok.com" => ("tiktok", after("video").or_else(|| after("photo"))?),
+        "twitter.com" | "x.com" | "fxtwitter.com" | "vxtwitter.com" | "fixupx.com" => {
+            ("twitter", after("status")?)
+        }
+        "instagram.com" => (
+            "instagram",
+            after("p")
+                .or_else(|| after("reel"))
+                .or_else(|| after("reels"))
+                .or_else(|| after("tv"))?,
+        ),
+        _ => return None,
+    };
+
+    Some(format!("{}:{}", site, id))
+}
+
+fn strip_tracking(url: &Url) -> String {
+    let mut params = url
+        .query_pairs()
+        .filter(|(key, _)| !key.starts_with("utm_") && !TRACKING_PARAMS.contains(&key.as_ref()))
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>();
+    params.sort();
+
+    let path = url.path().trim_end_matches('/');
+    match params.is_empty() {
+        true => format!("{}{}", host(url), path),
+        false => format!("{}{}?{}", host(url), path, params.join("&")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::test_config;
+
+    fn url(url: &str) -> Url {
+        Url::parse(url).unwrap()
+    }
+
+    #[test]
+    fn finds_canonical_ids() {
+        let cases = [
+            (
+                "https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=42",
+                "youtube:dQw4w9WgXcQ",
+            ),
+            ("https://youtu.be/dQw4w9WgXcQ?si=abc", "youtube:dQw4w9WgXcQ"),
+            ("https://m.youtube.com/shorts/abc123", "youtube:abc123"),
+            ("https://music.youtube.com/watch?v=xyz", "youtube:xyz"),
+            (
+                "https://www.tiktok.com/@user/video/7300000000000000000",
+                "tiktok:7300000000000000000",
+            ),
+            (
+                "https://x.com/user/status/1700000000000000000",
+                "twitter:1700000000000000000",
+            ),
+            (
+                "https://fxtwitter.com/user/status/1700000000000000000/photo/1",
+                "twitter:1700000000000000000",
+            ),
+            (
+                "https://www.instagram.com/reel/Cabc123/?igsh=xyz",
+                "instagram:Cabc123",
+            ),
+            ("https://instagram.com/p/Cdef456/", "instagram:Cdef456"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(
+                canonical_id(&url(input)).as_deref(),
+                Some(expected),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn no_canonical_id_for_other_sites_or_pages() {
+        assert_eq!(canonical_id(&url("https://example.com/video/1")), None);
+        assert_eq!(canonical_id(&url("https://www.youtube.com/@channel")), None);
+        assert_eq!(canonical_id(&url("https://x.com/user")), None);
+    }
+
+    #[test]
+    fn strips_tracking_params() {
+        assert_eq!(
+            strip_tracking(&url(
+                "https://www.reddit.com/r/videos/comments/abc/title/?utm_source=share&utm_medium=web&ref=x"
+            )),
+            "reddit.com/r/videos/comments/abc/title"
+        );
+        assert_eq!(
+            strip_tracking(&url("https://example.com/watch/?b=2&fbclid=123&a=1")),
+            "example.com/watch?a=1&b=2"
+        );
+    }
+
+    #[test]
+    fn recognises_short_links() {
+        assert!(is_short_link(&url("https://vm.tiktok.com/ZMabc/")));
+        assert!(is_short_link(&url("https://www.tiktok.com/t/ZTabc/")));
+        assert!(is_short_link(&url("https://t.co/abc")));
+        assert!(!is_short_link(&url("https://www.tiktok.com/@user/video/1")));
+    }
+
+    #[tokio::test]
+    async fn normalizes_the_same_post_to_the_same_key() {
+        let config = test_config();
+        let shared = normalize_url(
+            &config,
+            &url("https://twitter.com/user/status/1700000000000000000?s=20&t=abc"),
+        )
+        .await;
+        let copied = normalize_url(
+            &config,
+            &url("https://x.com/someone_else/status/1700000000000000000"),
+        )
+        .await;
+        assert_eq!(shared, copied);
+
+        assert_eq!(
+            normalize_url(&config, &url("https://Example.com/a/?utm_campaign=x")).await,
+            "example.com/a"
+        );
+    }
+}
@@ -0,0 +1,69 @@
+use std::sync::Arc;
+
+use twilight_model::channel::{Attachment, ChannelType};
+use vesper::prelude::*;
+
+use crate::cookies::save_cookie_file;
+use crate::AppContext;
+
+#[command(chat)]
+#[description = "Replace the cookie file used to download from a site"]
+pub async fn cookies(
+    ctx: &mut SlashContext<Arc<AppContext>>,
+    #[description = "Domain the cookies are for, e.g. instagram.com"] domain: String,
+    #[description = "Cookie file in the netscape format"] file: Attachment,
+) -> DefaultCommandResult {
+    ctx.defer(true).await?;
+
+    let is_admin = ctx
+        .interaction
+        .author_id()
+        .is_some_and(|id| ctx.data.config.admin_ids.contains(&id));
+
+    // Cookies are account credentials, keep them out of servers and group
+    // chats entirely, only a DM with the bot will do
+    let in_dm = ctx.interaction.guild_id.is_none()
+        && ctx
+            .interaction
+            .channel
+            .as_ref()
+            .is_some_and(|channel| channel.kind == ChannelType::Private);
+
+    let content = if !is_admin {
+        "You're not allowed to do that".to_string()
+    } else if !in_dm {
+        "Send cookies in a DM with me, not in a server or group".to_string()
+    } else {
+        match update_cookies(ctx.data, &domain, &file).await {
+            Ok(()) => format!("Updated cookies for {}", domain),
+            Err(err) => {
+                tracing::error!(domain, "failed to update cookies: {}", err);
+                format!("Failed to update cookies: {}", err)
+            }
+        }
+    };
+
+    ctx.interaction_client
+        .update_response(&ctx.interaction.token)
+        .content(Some(&content))?
+        .await?;
+
+    Ok(())
+}
+
+async fn update_cookies(
+    ctx: &AppContext,
+    domain: &str,
+    file: &Attachment,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let contents = reqwest::get(&file.url)
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+
+    let path = save_cookie_file(&ctx.config, domain, &contents).await?;
+    tracing::info!(?path, "updated cookies");
+
+    Ok(())
+}
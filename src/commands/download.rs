@@ -1,17 +1,24 @@
-use std::fs;
 use std::sync::Arc;
-use std::time::Instant;
 
+use tokio_util::sync::CancellationToken;
 use twilight_model::channel::message::embed::EmbedField;
 use url::Url;
 use vesper::prelude::*;
 
+use crate::cache::{cache_key, show_cached};
 use crate::embed::MieEmbed;
 use crate::errors::MieError;
-use crate::upload::{self, upload_files};
-use crate::video::download_video;
+use crate::media::MediaOrigin;
+use crate::metrics::{job_finished, Outcome};
+use crate::playlist::mirror_playlist;
+use crate::probe::{plan_download, DownloadPlan};
+use crate::quota::check_quota;
+use crate::stage::{download_with_retry, run_stage, upload_video, Stage};
+use crate::video::Quality;
 use crate::AppContext;
 
+use super::{post_links, ResponseProgress};
+
 #[command(chat)]
 #[description = "Download a video"]
 pub async fn download(
@@ -19,7 +26,7 @@ pub async fn download(
     #[description = "URL To Download"] url: String,
     #[description = "Extra text to inlude in message"] content: Option<String>,
 ) -> DefaultCommandResult {
-    match download_inner(ctx, url, content).await {
+    match download_inner(ctx, url.clone(), content).await {
         Ok(val) => Ok(val),
         // Err(MieError::VideoDownloadFailed(video)) => {
         //     let channel = ctx.interaction.channel.clone().unwrap();
@@ -35,6 +42,12 @@ pub async fn download(
         //     Err(video)
         // }
         Err(err) => {
+            let outcome = err
+                .downcast_ref::<MieError>()
+                .map(Outcome::from)
+                .unwrap_or(Outcome::Failed);
+            job_finished(&url, outcome);
+
             let channel = ctx.interaction.channel.clone().unwrap();
             let channel_id = channel.id;
             let mut embed = MieEmbed::new(ctx.data.clone(), channel_id);
@@ -49,6 +62,15 @@ pub async fn download(
                     MieError::YtDlError(_) => {
                         error_embed = embed.title("ytdlp errored".to_string());
                     }
+                    MieError::FfmpegError(_) => {
+                        error_embed = embed.title("ffmpeg errored".to_string());
+                    }
+                    MieError::ProbeFailed(_) => {
+                        error_embed = embed.title("ytdlp errored".to_string());
+                    }
+                    _ => {
+                        error_embed = embed.title(mie_error.to_string());
+                    }
                 }
             } else {
                 tracing::error!("unhandled error: {}", err.to_string());
@@ -89,6 +111,38 @@ async fn download_inner(
     let channel_id = channel.id;
     let mut embed = MieEmbed::new(ctx.data.clone(), channel_id);
 
+    let uploader_id = ctx
+        .interaction
+        .author_id()
+        .ok_or("interaction has no author")?;
+    let cache_key = cache_key(
+        &ctx.data.config,
+        &video_url,
+        ctx.interaction.guild_id,
+        uploader_id,
+    )
+    .await;
+    let cached = ctx
+        .data
+        .cache
+        .get_signed(&cache_key, &ctx.data.config, &ctx.data.b2.basic_client())
+        .await;
+    if let Some(cached) = cached {
+        tracing::info!(url, cache_key, "using cached result");
+        job_finished(&url, Outcome::Cached);
+        ctx.interaction_client
+            .update_response(&ctx.interaction.token)
+            .embeds(Some(&[show_cached(&mut embed, &cached).build()]))?
+            .await?;
+
+        let content = format!("{} {}", content.unwrap_or_default(), cached.links.join(" "));
+        post_links(ctx, &content, &cached.all_files()).await?;
+
+        return Ok(());
+    }
+
+    let cancel = CancellationToken::new();
+
     // Let user know we are downloading their URL
     // also ensures we have permissions to send messages in this channel
     ctx.interaction_client
@@ -96,7 +150,68 @@ async fn download_inner(
         .embeds(Some(&[embed.title("Downloading".to_string()).build()]))?
         .await?;
 
-    let downloaded_video = download_video(&video_url.to_string()).await?;
+    let plan = run_stage(
+        &ctx.data.config,
+        Stage::Probe,
+        &cancel,
+        plan_download(&ctx.data.config, &video_url),
+    )
+    .await?;
+
+    let (quality, estimated_size) = match plan {
+        // Confirmed and reported on a followup everyone can see, the
+        // deferred response is only visible to whoever ran the command
+        DownloadPlan::Playlist(playlist) => {
+            ctx.interaction_client
+                .update_response(&ctx.interaction.token)
+                .embeds(Some(&[embed
+                    .title(format!("Playlist: {}", playlist.title))
+                    .build()]))?
+                .await?;
+
+            let mut followup = MieEmbed::followup(
+                ctx.data.clone(),
+                channel_id,
+                ctx.interaction.id,
+                ctx.interaction.token.clone(),
+            );
+            mirror_playlist(
+                ctx.data.clone(),
+                &mut followup,
+                playlist,
+                uploader_id,
+                ctx.interaction.guild_id,
+            )
+            .await?;
+
+            return Ok(());
+        }
+        DownloadPlan::Download(quality, estimated_size) => (quality, estimated_size),
+    };
+
+    if quality == Quality::Low {
+        ctx.interaction_client
+            .update_response(&ctx.interaction.token)
+            .embeds(Some(&[embed
+                .title("Too large at best quality, downloading 480p".to_string())
+                .build()]))?
+            .await?;
+    }
+
+    let downloaded_video = download_with_retry(
+        ctx.data,
+        video_url.as_str(),
+        quality,
+        estimated_size,
+        &cancel,
+        &mut ResponseProgress {
+            client: &ctx.interaction_client,
+            token: &ctx.interaction.token,
+            embed: &mut embed,
+            upload_field: 1,
+        },
+    )
+    .await?;
 
     ctx.interaction_client
         .update_response(&ctx.interaction.token)
@@ -115,83 +230,97 @@ async fn download_inner(
             .build()]))?
         .await?;
 
-    let files = vec![upload::UploadFile {
-        path: downloaded_video.path.clone(),
-    }];
-
-    let bucket = Arc::new(ctx.data.config.b2_bucket_id.clone())
-        .as_str()
-        .into();
-
-    let upload_start = Instant::now();
+    check_quota(
+        ctx.data,
+        ctx.interaction.guild_id,
+        uploader_id,
+        downloaded_video.size(),
+    )?;
 
     tracing::info!(url, "uploading start");
 
-    let uploaded_files = upload_files(
-        ctx.data.b2.clone(),
-        bucket,
-        files,
-        Some(move |_path: &str, uploaded, total, percentage, bps, eta| {
-            tracing::trace!(uploaded, total, percentage, bps, eta, "uploading")
-            // let write = (uploaded, total, percentage, bps);
-            // set_last_update_data.send(write).ok();
-        }),
+    let mirrored = upload_video(
+        ctx.data,
+        &downloaded_video,
+        uploader_id,
+        &cancel,
+        &mut ResponseProgress {
+            client: &ctx.interaction_client,
+            token: &ctx.interaction.token,
+            embed: &mut embed,
+            upload_field: 1,
+        },
     )
     .await;
 
-    let _ = fs::remove_file(downloaded_video.path);
+    let mirrored = match mirrored {
+        Ok(mirrored) => mirrored,
+        Err(err) => {
+            tracing::error!("failed to upload files: {:?}", err);
+            job_finished(&url, Outcome::Failed);
+            ctx.interaction_client
+                .update_response(&ctx.interaction.token)
+                .embeds(Some(&[embed
+                    .title(format!("failed to upload video: {}", err))
+                    .update_field(
+                        1,
+                        EmbedField {
+                            name: "Upload".to_string(),
+                            value: "Error".to_string(),
+                            inline: true,
+                        },
+                    )
+                    .build()]))?
+                .await?;
+            return Ok(());
+        }
+    };
 
-    let upload_time = upload_start.elapsed().as_millis();
+    job_finished(&url, Outcome::Success);
 
-    if let Err(err) = uploaded_files {
-        tracing::error!("failed to upload files: {:?}", err);
-        ctx.interaction_client
-            .update_response(&ctx.interaction.token)
-            .embeds(Some(&[embed
-                .title("failed to upload video".to_string())
-                .update_field(
-                    1,
-                    EmbedField {
-                        name: "Upload".to_string(),
-                        value: "Error".to_string(),
-                        inline: true,
-                    },
-                )
-                .build()]))?
-            .await?;
-        return Ok(());
+    let links = &mirrored.links;
+    if downloaded_video.is_gallery() {
+        let mut title = format!("Downloaded {} files", links.len());
+        if mirrored.failed > 0 {
+            title += &format!(", {} failed to upload", mirrored.failed);
+        }
+        embed.title(title).description(links.join("\n"));
+    } else {
+        embed.title(format!("Download: {}", links[0]));
     }
 
-    tracing::info!(url, "uploading complete in {}ms", upload_time);
+    let content = format!("{} {}", content.unwrap_or_default(), links.join(" "));
+    let files = mirrored
+        .uploaded
+        .iter()
+        .map(|uploaded| uploaded.file_name.clone())
+        .collect::<Vec<_>>();
+    let message_id = post_links(ctx, &content, &files).await;
+
+    // Interaction responses expire, the uploads belong to the followup with
+    // the links. Still recorded when posting it failed so they count and expire
+    let origin = MediaOrigin {
+        guild_id: ctx.interaction.guild_id,
+        channel_id: Some(channel_id),
+        message_id: message_id.as_ref().ok().copied(),
+        uploader_id,
+    };
+    mirrored.save(ctx.data, &origin, &downloaded_video, Some(&cache_key));
+    message_id?;
+
     ctx.interaction_client
         .update_response(&ctx.interaction.token)
         .embeds(Some(&[embed
-            .title(format!(
-                "Download: https://cdn.avrg.dev/{}/{}.mp4",
-                ctx.data.config.b2_bucket_path_prefix, downloaded_video.downloaded_file_name
-            ))
             .update_field(
                 1,
                 EmbedField {
                     name: "Upload".to_string(),
-                    value: format!("{}ms", upload_time),
+                    value: format!("{}ms", mirrored.upload_time),
                     inline: true,
                 },
             )
             .build()]))?
         .await?;
-    ctx.interaction_client
-        .create_followup(&ctx.interaction.token)
-        .content(
-            format!(
-                "{} https://cdn.avrg.dev/{}/{}.mp4",
-                content.unwrap_or("".to_string()),
-                ctx.data.config.b2_bucket_path_prefix,
-                downloaded_video.downloaded_file_name
-            )
-            .as_str(),
-        )?
-        .await?;
 
     tracing::info!("donme?");
 
@@ -0,0 +1,260 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use tokio_util::sync::CancellationToken;
+use twilight_model::channel::message::embed::EmbedField;
+use url::Url;
+use vesper::prelude::*;
+
+use crate::convert::{convert_to_animation, AnimationFormat, AnimationOptions};
+use crate::embed::MieEmbed;
+use crate::errors::MieError;
+use crate::media::MediaOrigin;
+use crate::metrics::{job_finished, Outcome};
+use crate::probe::{plan_download, DownloadPlan};
+use crate::quota::check_quota;
+use crate::stage::{download_with_retry, run_stage, upload_with_retry, Stage};
+use crate::upload::UploadFile;
+use crate::AppContext;
+
+use super::{post_links, ResponseProgress};
+
+#[derive(Parse)]
+pub enum Format {
+    #[parse(rename = "gif")]
+    Gif,
+    #[parse(rename = "webp")]
+    WebP,
+}
+
+impl From<Format> for AnimationFormat {
+    fn from(format: Format) -> Self {
+        match format {
+            Format::Gif => AnimationFormat::Gif,
+            Format::WebP => AnimationFormat::WebP,
+        }
+    }
+}
+
+#[command(chat)]
+#[description = "Turn a video into a gif or animated webp"]
+pub async fn gif(
+    ctx: &mut SlashContext<Arc<AppContext>>,
+    #[description = "URL To Download"] url: String,
+    #[description = "Output format, defaults to gif"] format: Option<Format>,
+    #[description = "Second to start from"] start: Option<f64>,
+    #[description = "Length in seconds, max 15"] duration: Option<f64>,
+    #[description = "Frames per second, max 30"] fps: Option<i64>,
+    #[description = "Width in pixels, max 720"] width: Option<i64>,
+) -> DefaultCommandResult {
+    let options = AnimationOptions::new(
+        format.map_or(AnimationFormat::Gif, AnimationFormat::from),
+        fps,
+        width,
+        start,
+        duration,
+    );
+
+    if let Err(err) = gif_inner(ctx, url.clone(), options).await {
+        tracing::error!("failed to convert video: {}", err.to_string());
+        let outcome = err
+            .downcast_ref::<MieError>()
+            .map(Outcome::from)
+            .unwrap_or(Outcome::Failed);
+        job_finished(&url, outcome);
+
+        let channel = ctx.interaction.channel.clone().unwrap();
+        let mut embed = MieEmbed::new(ctx.data.clone(), channel.id);
+        ctx.interaction_client
+            .update_response(&ctx.interaction.token)
+            .embeds(Some(&[embed
+                .title(format!("failed to convert video: {}", err))
+                .build()]))?
+            .await?;
+        return Err(err);
+    }
+
+    Ok(())
+}
+
+async fn gif_inner(
+    ctx: &mut SlashContext<'_, Arc<AppContext>>,
+    url: String,
+    options: AnimationOptions,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    ctx.defer(true).await?;
+    let is_http = url.starts_with("https://") || url.starts_with("http://");
+
+    if !is_http {
+        ctx.interaction_client
+            .update_response(&ctx.interaction.token)
+            .content(Some("give me a link you stupid fuck"))?
+            .await?;
+
+        return Ok(());
+    }
+
+    let video_url = Url::parse(&url)?;
+    let channel = ctx.interaction.channel.clone().unwrap();
+    let mut embed = MieEmbed::new(ctx.data.clone(), channel.id);
+    let cancel = CancellationToken::new();
+
+    ctx.interaction_client
+        .update_response(&ctx.interaction.token)
+        .embeds(Some(&[embed.title("Downloading".to_string()).build()]))?
+        .await?;
+
+    let plan = run_stage(
+        &ctx.data.config,
+        Stage::Probe,
+        &cancel,
+        plan_download(&ctx.data.config, &video_url),
+    )
+    .await?;
+
+    let (quality, estimated_size) = match plan {
+        DownloadPlan::Playlist(_) => {
+            ctx.interaction_client
+                .update_response(&ctx.interaction.token)
+                .embeds(Some(&[embed
+                    .title("Playlists can't be turned into a gif".to_string())
+                    .build()]))?
+                .await?;
+
+            return Ok(());
+        }
+        DownloadPlan::Download(quality, estimated_size) => (quality, estimated_size),
+    };
+
+    let downloaded_video = download_with_retry(
+        ctx.data,
+        video_url.as_str(),
+        quality,
+        estimated_size,
+        &cancel,
+        &mut ResponseProgress {
+            client: &ctx.interaction_client,
+            token: &ctx.interaction.token,
+            embed: &mut embed,
+            upload_field: 2,
+        },
+    )
+    .await?;
+
+    ctx.interaction_client
+        .update_response(&ctx.interaction.token)
+        .embeds(Some(&[embed
+            .title(format!("Converting to {}...", options.format.extension()))
+            .add_field(EmbedField {
+                name: "Download".to_string(),
+                value: format!("{}ms", downloaded_video.download_time),
+                inline: true,
+            })
+            .add_field(EmbedField {
+                name: "Convert".to_string(),
+                value: "Processing".to_string(),
+                inline: true,
+            })
+            .build()]))?
+        .await?;
+
+    let converted = run_stage(
+        &ctx.data.config,
+        Stage::Transcode,
+        &cancel,
+        convert_to_animation(&downloaded_video, &options),
+    )
+    .await?;
+
+    ctx.interaction_client
+        .update_response(&ctx.interaction.token)
+        .embeds(Some(&[embed
+            .title("Converted, uploading...".to_string())
+            .update_field(
+                1,
+                EmbedField {
+                    name: "Convert".to_string(),
+                    value: format!(
+                        "{}ms ({:.1} MB)",
+                        converted.convert_time,
+                        converted.size as f64 / 1_000_000.0
+                    ),
+                    inline: true,
+                },
+            )
+            .add_field(EmbedField {
+                name: "Upload".to_string(),
+                value: "Processing".to_string(),
+                inline: true,
+            })
+            .build()]))?
+        .await?;
+
+    let uploader_id = ctx
+        .interaction
+        .author_id()
+        .ok_or("interaction has no author")?;
+    check_quota(
+        ctx.data,
+        ctx.interaction.guild_id,
+        uploader_id,
+        converted.size,
+    )?;
+
+    let upload_start = Instant::now();
+    let files = vec![UploadFile {
+        content_type: Some(converted.content_type.clone()),
+        ..downloaded_video.upload(&converted.path, uploader_id)
+    }];
+
+    let uploaded = upload_with_retry(
+        ctx.data,
+        files,
+        &cancel,
+        &mut ResponseProgress {
+            client: &ctx.interaction_client,
+            token: &ctx.interaction.token,
+            embed: &mut embed,
+            upload_field: 2,
+        },
+    )
+    .await?
+    .remove(0)?;
+
+    let upload_time = upload_start.elapsed().as_millis();
+    job_finished(&url, Outcome::Success);
+    let link = uploaded.url.clone();
+
+    // Send the bare link so discord embeds and autoplays it
+    let message_id = post_links(ctx, &link, std::slice::from_ref(&uploaded.file_name)).await;
+
+    // Interaction responses expire, the upload belongs to the followup with
+    // the link. Still recorded when posting it failed so it counts and expires
+    let origin = MediaOrigin {
+        guild_id: ctx.interaction.guild_id,
+        channel_id: Some(channel.id),
+        message_id: message_id.as_ref().ok().copied(),
+        uploader_id,
+    };
+    ctx.data
+        .media
+        .record(&origin, &downloaded_video, None, [&uploaded]);
+    message_id?;
+
+    ctx.interaction_client
+        .update_response(&ctx.interaction.token)
+        .embeds(Some(&[embed
+            .title(format!("Download: {}", link))
+            .update_field(
+                2,
+                EmbedField {
+                    name: "Upload".to_string(),
+                    value: format!("{}ms", upload_time),
+                    inline: true,
+                },
+            )
+            .build()]))?
+        .await?;
+
+    Ok(())
+}
@@ -1 +1,82 @@
+use std::error::Error;
+use std::sync::Arc;
+
+use twilight_http::client::InteractionClient;
+use twilight_model::channel::message::embed::EmbedField;
+use twilight_model::id::marker::MessageMarker;
+use twilight_model::id::Id;
+use vesper::prelude::*;
+
+use crate::embed::MieEmbed;
+use crate::links::refresh_components;
+use crate::retry::Backoff;
+use crate::stage::{Progress, Stage};
+use crate::AppContext;
+
+pub mod cookies;
 pub mod download;
+pub mod gif;
+pub mod status;
+pub mod usage;
+
+fn gigabytes(bytes: u64) -> String {
+    format!("{:.2} GB", bytes as f64 / 1_000_000_000.0)
+}
+
+/// Shows retries on a deferred interaction response.
+struct ResponseProgress<'a> {
+    client: &'a InteractionClient<'a>,
+    token: &'a str,
+    embed: &'a mut MieEmbed,
+    // Commands put the upload field in different places
+    upload_field: usize,
+}
+
+impl Progress for ResponseProgress<'_> {
+    async fn retrying(&mut self, stage: Stage, backoff: &Backoff) {
+        match stage {
+            Stage::Upload => self.embed.update_field(
+                self.upload_field,
+                EmbedField {
+                    name: "Upload".to_string(),
+                    value: backoff.describe(),
+                    inline: true,
+                },
+            ),
+            _ => self.embed.title(backoff.describe()),
+        };
+
+        let embeds = [self.embed.build()];
+        let update = self
+            .client
+            .update_response(self.token)
+            .embeds(Some(&embeds));
+        let result = match update {
+            Ok(update) => update.await.map(|_| ()).map_err(|err| err.to_string()),
+            Err(err) => Err(err.to_string()),
+        };
+        if let Err(err) = result {
+            tracing::warn!("failed to show retry: {}", err);
+        }
+    }
+}
+
+/// Posts the links where everyone can see them, with a button to get fresh
+/// ones once they expire. Returns the message so uploads can be tied to it.
+async fn post_links(
+    ctx: &SlashContext<'_, Arc<AppContext>>,
+    content: &str,
+    files: &[String],
+) -> Result<Id<MessageMarker>, Box<dyn Error + Send + Sync>> {
+    let message = ctx
+        .interaction_client
+        .create_followup(&ctx.interaction.token)
+        .content(content)?
+        .components(&refresh_components(&ctx.data.config))?
+        .await?
+        .model()
+        .await?;
+
+    ctx.data.media.attach(message.channel_id, message.id, files);
+    Ok(message.id)
+}
@@ -0,0 +1,53 @@
+use std::sync::Arc;
+
+use twilight_model::channel::message::embed::EmbedField;
+use vesper::prelude::*;
+
+use crate::commands::gigabytes;
+use crate::disk::disk_usage;
+use crate::embed::MieEmbed;
+use crate::AppContext;
+
+#[command(chat)]
+#[description = "Show disk usage and queued jobs"]
+pub async fn status(ctx: &mut SlashContext<Arc<AppContext>>) -> DefaultCommandResult {
+    ctx.defer(true).await?;
+
+    let usage = disk_usage(&ctx.data.config.work_dir)?;
+    let channel = ctx.interaction.channel.clone().unwrap();
+    let mut embed = MieEmbed::new(ctx.data.clone(), channel.id);
+
+    embed
+        .title("mie status".to_string())
+        .add_field(EmbedField {
+            name: "Disk used".to_string(),
+            value: format!(
+                "{} / {}",
+                gigabytes(usage.total - usage.available),
+                gigabytes(usage.total)
+            ),
+            inline: true,
+        })
+        .add_field(EmbedField {
+            name: "Available".to_string(),
+            value: gigabytes(usage.available),
+            inline: true,
+        })
+        .add_field(EmbedField {
+            name: "Reserved".to_string(),
+            value: gigabytes(ctx.data.disk.reserved()),
+            inline: true,
+        })
+        .add_field(EmbedField {
+            name: "Waiting for space".to_string(),
+            value: ctx.data.disk.queued().to_string(),
+            inline: true,
+        });
+
+    ctx.interaction_client
+        .update_response(&ctx.interaction.token)
+        .embeds(Some(&[embed.build()]))?
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,107 @@
+use std::sync::Arc;
+
+use twilight_model::channel::message::embed::EmbedField;
+use vesper::prelude::*;
+
+use crate::commands::gigabytes;
+use crate::embed::MieEmbed;
+use crate::quota::Usage;
+use crate::AppContext;
+
+const TOP_UPLOADERS: usize = 5;
+
+fn used(bytes: u64, quota: Option<u64>) -> String {
+    match quota {
+        Some(quota) => format!("{} / {}", gigabytes(bytes), gigabytes(quota)),
+        None => gigabytes(bytes),
+    }
+}
+
+#[command(chat)]
+#[description = "Show storage used by this server and its top uploaders"]
+pub async fn usage(ctx: &mut SlashContext<Arc<AppContext>>) -> DefaultCommandResult {
+    ctx.defer(true).await?;
+
+    let config = &ctx.data.config;
+    let records = ctx.data.media.all()?;
+    let user_id = ctx
+        .interaction
+        .author_id()
+        .ok_or("interaction has no author")?;
+    let channel = ctx.interaction.channel.clone().unwrap();
+    let mut embed = MieEmbed::new(ctx.data.clone(), channel.id);
+
+    let user = Usage::of(
+        records
+            .iter()
+            .filter(|record| record.uploader_id == user_id),
+    );
+
+    match ctx.interaction.guild_id {
+        Some(guild_id) => {
+            let guild = Usage::of(
+                records
+                    .iter()
+                    .filter(|record| record.guild_id == Some(guild_id)),
+            );
+            let top = guild
+                .uploaders
+                .iter()
+                .take(TOP_UPLOADERS)
+                .enumerate()
+                .map(|(index, (id, bytes, files))| {
+                    format!(
+                        "{}. <@{}> {} ({} files)",
+                        index + 1,
+                        id,
+                        gigabytes(*bytes),
+                        files
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            embed
+                .title("Storage used in this server".to_string())
+                .add_field(EmbedField {
+                    name: "Used".to_string(),
+                    value: used(guild.bytes, config.guild_quota),
+                    inline: true,
+                })
+                .add_field(EmbedField {
+                    name: "Files".to_string(),
+                    value: guild.files.to_string(),
+                    inline: true,
+                })
+                .add_field(EmbedField {
+                    name: "You".to_string(),
+                    value: used(user.bytes, config.user_quota),
+                    inline: true,
+                });
+
+            if !top.is_empty() {
+                embed.description(format!("**Top uploaders**\n{}", top.join("\n")));
+            }
+        }
+        None => {
+            embed
+                .title("Your storage".to_string())
+                .add_field(EmbedField {
+                    name: "Used".to_string(),
+                    value: used(user.bytes, config.user_quota),
+                    inline: true,
+                })
+                .add_field(EmbedField {
+                    name: "Files".to_string(),
+                    value: user.files.to_string(),
+                    inline: true,
+                });
+        }
+    }
+
+    ctx.interaction_client
+        .update_response(&ctx.interaction.token)
+        .embeds(Some(&[embed.build()]))?
+        .await?;
+
+    Ok(())
+}
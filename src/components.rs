@@ -0,0 +1,84 @@
+use std::sync::Mutex;
+
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use twilight_model::application::interaction::{Interaction, InteractionData};
+use twilight_model::channel::message::component::{ActionRow, Button, ButtonStyle};
+use twilight_model::channel::message::Component;
+use twilight_model::http::interaction::{
+    InteractionResponse, InteractionResponseData, InteractionResponseType,
+};
+
+use crate::metrics;
+use crate::AppContext;
+
+/// Routes button presses to whatever is waiting on them, vesper can only do
+/// this from inside a slash command so the message handler needs its own.
+#[derive(Default)]
+pub struct ComponentWaiters {
+    waiters: Mutex<Vec<(String, UnboundedSender<Interaction>)>>,
+}
+
+impl ComponentWaiters {
+    /// Receives every component interaction with a custom id starting with
+    /// `prefix` until the receiver is dropped.
+    pub fn wait(&self, prefix: String) -> UnboundedReceiver<Interaction> {
+        let (sender, receiver) = unbounded_channel();
+        self.waiters.lock().unwrap().push((prefix, sender));
+        receiver
+    }
+
+    /// Hands the interaction to a waiter, returning it back if nobody wanted it.
+    pub fn wake(&self, interaction: Interaction) -> Option<Interaction> {
+        let Some(InteractionData::MessageComponent(data)) = &interaction.data else {
+            return Some(interaction);
+        };
+
+        let mut waiters = self.waiters.lock().unwrap();
+        waiters.retain(|(_, sender)| !sender.is_closed());
+
+        match waiters
+            .iter()
+            .find(|(prefix, _)| data.custom_id.starts_with(prefix.as_str()))
+        {
+            Some((_, sender)) => sender.send(interaction).err().map(|err| err.0),
+            None => Some(interaction),
+        }
+    }
+}
+
+pub fn button(custom_id: String, label: &str, style: ButtonStyle) -> Component {
+    Component::Button(Button {
+        custom_id: Some(custom_id),
+        disabled: false,
+        emoji: None,
+        label: Some(label.to_string()),
+        style,
+        url: None,
+    })
+}
+
+pub fn action_row(components: Vec<Component>) -> Component {
+    Component::ActionRow(ActionRow { components })
+}
+
+pub async fn respond(
+    ctx: &AppContext,
+    interaction: &Interaction,
+    kind: InteractionResponseType,
+    data: Option<InteractionResponseData>,
+) {
+    let response = ctx
+        .http
+        .interaction(ctx.application_id)
+        .create_response(
+            interaction.id,
+            &interaction.token,
+            &InteractionResponse { kind, data },
+        )
+        .await;
+
+    if let Err(err) = response {
+        metrics::discord_error("interaction_response");
+        tracing::warn!("failed to respond to component interaction: {:?}", err);
+    }
+}
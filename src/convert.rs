@@ -0,0 +1,149 @@
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Instant;
+
+use tokio::process::Command;
+
+use crate::errors::MieError;
+use crate::video::DownloadedVideo;
+
+pub const MAX_DURATION: f64 = 15.0;
+pub const DEFAULT_FPS: u32 = 15;
+pub const DEFAULT_WIDTH: u32 = 480;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnimationFormat {
+    Gif,
+    WebP,
+}
+
+impl AnimationFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AnimationFormat::Gif => "gif",
+            AnimationFormat::WebP => "webp",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            AnimationFormat::Gif => "image/gif",
+            AnimationFormat::WebP => "image/webp",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AnimationOptions {
+    pub format: AnimationFormat,
+    pub fps: u32,
+    pub width: u32,
+    pub start: f64,
+    pub duration: f64,
+}
+
+impl AnimationOptions {
+    /// Clamps user provided values to something discord will still autoplay
+    /// and that won't take forever to encode.
+    pub fn new(
+        format: AnimationFormat,
+        fps: Option<i64>,
+        width: Option<i64>,
+        start: Option<f64>,
+        duration: Option<f64>,
+    ) -> Self {
+        AnimationOptions {
+            format,
+            fps: fps.map_or(DEFAULT_FPS, |fps| fps.clamp(1, 30) as u32),
+            width: width.map_or(DEFAULT_WIDTH, |width| width.clamp(64, 720) as u32),
+            start: start.unwrap_or(0.0).max(0.0),
+            duration: duration.unwrap_or(MAX_DURATION).clamp(0.5, MAX_DURATION),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ConvertedAnimation {
+    pub path: String,
+    pub content_type: String,
+    pub size: u64,
+    pub convert_time: u128,
+}
+
+/// Converts the downloaded video into a looping animation, gifs go through
+/// palettegen/paletteuse so they don't end up as a dithered mess.
+pub async fn convert_to_animation(
+    video: &DownloadedVideo,
+    options: &AnimationOptions,
+) -> Result<ConvertedAnimation, MieError> {
+    let process_start = Instant::now();
+    let file_name = format!(
+        "{}.{}",
+        video.downloaded_file_name,
+        options.format.extension()
+    );
+    let path = video.workspace.path(&file_name);
+
+    let scale = format!(
+        "fps={},scale={}:-2:flags=lanczos",
+        options.fps, options.width
+    );
+
+    let mut command = Command::new("ffmpeg");
+    command
+        .args(["-y", "-v", "error"])
+        .args(["-ss", &options.start.to_string()])
+        .args(["-t", &options.duration.to_string()])
+        .args(["-i", &video.path, "-an"]);
+
+    match options.format {
+        AnimationFormat::Gif => {
+            command.arg("-filter_complex").arg(format!(
+                "[0:v]{},split[a][b];[a]palettegen=stats_mode=diff[p];[b][p]paletteuse=dither=bayer:bayer_scale=5:diff_mode=rectangle",
+                scale
+            ));
+        }
+        AnimationFormat::WebP => {
+            command.arg("-vf").arg(scale).args([
+                "-c:v",
+                "libwebp",
+                "-lossless",
+                "0",
+                "-q:v",
+                "70",
+                "-compression_level",
+                "6",
+            ]);
+        }
+    }
+
+    let output = command
+        .args(["-loop", "0", &path])
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|err| MieError::FfmpegError(err.to_string()))?;
+
+    if !output.status.success() || !Path::new(&path).is_file() {
+        return Err(MieError::FfmpegError(
+            String::from_utf8_lossy(&output.stderr).to_string(),
+        ));
+    }
+
+    let size = tokio::fs::metadata(&path)
+        .await
+        .map_err(|err| MieError::FfmpegError(err.to_string()))?
+        .len();
+
+    let convert_time = process_start.elapsed().as_millis();
+    tracing::info!(video.path, size, "Converting took {}ms", convert_time);
+
+    Ok(ConvertedAnimation {
+        path,
+        content_type: options.format.content_type().to_string(),
+        size,
+        convert_time,
+    })
+}
@@ -0,0 +1,140 @@
+use std::path::{Path, PathBuf};
+
+use url::Url;
+
+use crate::env::Config;
+use crate::errors::MieError;
+
+/// Finds the cookie file for the url's site, files live in the cookies dir
+/// named after the domain, e.g. `instagram.com.txt`. Subdomains fall back to
+/// their parent domain and `default.txt` is used when nothing else matches.
+pub fn cookie_file(config: &Config, url: &str) -> Option<PathBuf> {
+    let dir = Path::new(config.cookies_dir.as_ref()?);
+    let host = Url::parse(url).ok()?.host_str()?.to_lowercase();
+
+    let mut domain = host.as_str();
+    loop {
+        let path = dir.join(format!("{}.txt", domain));
+        if path.is_file() {
+            return Some(path);
+        }
+
+        match domain.split_once('.') {
+            // Stop before trying just the tld
+            Some((_, parent)) if parent.contains('.') => domain = parent,
+            _ => break,
+        }
+    }
+
+    Some(dir.join("default.txt")).filter(|path| path.is_file())
+}
+
+/// Extra yt-dlp arguments for logging in with the given cookie file and
+/// any configured credentials, as pairs of the flag and its value.
+pub fn auth_args(config: &Config, cookies: Option<&Path>) -> Vec<(String, Option<String>)> {
+    let mut args = vec![];
+
+    if let Some(cookies) = cookies {
+        args.push((
+            "--cookies".to_string(),
+            Some(cookies.to_string_lossy().to_string()),
+        ));
+    }
+
+    // Credentials go in a netrc file with the extractor name as the machine
+    if let Some(netrc) = &config.netrc_file {
+        args.push(("--netrc".to_string(), None));
+        args.push(("--netrc-location".to_string(), Some(netrc.clone())));
+    }
+
+    args
+}
+
+/// Replaces the cookie file for `domain`, written to a temporary file first
+/// so a running download never sees half a file.
+pub async fn save_cookie_file(
+    config: &Config,
+    domain: &str,
+    contents: &str,
+) -> Result<PathBuf, MieError> {
+    let Some(dir) = &config.cookies_dir else {
+        return Err(MieError::CookieError("COOKIES_DIR is not set".to_string()));
+    };
+
+    let domain = domain.trim().trim_start_matches("www.").to_lowercase();
+    let is_valid_domain = !domain.is_empty()
+        && domain
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-');
+
+    if !is_valid_domain {
+        return Err(MieError::CookieError(format!("invalid domain: {}", domain)));
+    }
+
+    if !is_netscape_cookie_file(contents) {
+        return Err(MieError::CookieError(
+            "file is not in the netscape cookie format".to_string(),
+        ));
+    }
+
+    let path = Path::new(dir).join(format!("{}.txt", domain));
+    let temp_path = Path::new(dir).join(format!(".{}.txt.tmp", domain));
+
+    tokio::fs::create_dir_all(dir)
+        .await
+        .map_err(|err| MieError::CookieError(err.to_string()))?;
+    tokio::fs::write(&temp_path, contents)
+        .await
+        .map_err(|err| MieError::CookieError(err.to_string()))?;
+    tokio::fs::rename(&temp_path, &path)
+        .await
+        .map_err(|err| MieError::CookieError(err.to_string()))?;
+
+    Ok(path)
+}
+
+fn is_netscape_cookie_file(contents: &str) -> bool {
+    let mut cookies = contents
+        .lines()
+        .map(str::trim_end)
+        .filter(|line| !line.is_empty())
+        // Http only cookies are prefixed, everything else starting with # is a comment
+        .map(|line| line.strip_prefix("#HttpOnly_").unwrap_or(line))
+        .filter(|line| !line.starts_with('#'))
+        .peekable();
+
+    cookies.peek().is_some() && cookies.all(|line| line.split('\t').count() == 7)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_netscape_cookie_files() {
+        let contents = "# Netscape HTTP Cookie File\n\
+            # This is a generated file! Do not edit.\n\
+            \n\
+            .instagram.com\tTRUE\t/\tTRUE\t1790000000\tcsrftoken\tabc\n\
+            #HttpOnly_.instagram.com\tTRUE\t/\tTRUE\t1790000000\tsessionid\tdef\n";
+        assert!(is_netscape_cookie_file(contents));
+        assert!(is_netscape_cookie_file(
+            "example.com\tFALSE\t/\tFALSE\t0\tname\tvalue\r\n"
+        ));
+    }
+
+    #[test]
+    fn rejects_anything_else() {
+        assert!(!is_netscape_cookie_file(""));
+        assert!(!is_netscape_cookie_file("# Netscape HTTP Cookie File\n"));
+        assert!(!is_netscape_cookie_file(
+            r#"[{"name": "sessionid", "value": "def"}]"#
+        ));
+        assert!(!is_netscape_cookie_file("sessionid=def; csrftoken=abc"));
+        // One good line doesn't make up for a broken one
+        assert!(!is_netscape_cookie_file(
+            ".instagram.com\tTRUE\t/\tTRUE\t1790000000\tcsrftoken\tabc\n\
+             .instagram.com\tTRUE\t/\tsessionid\n"
+        ));
+    }
+}
@@ -0,0 +1,290 @@
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Instant;
+
+use tokio::process::Command;
+
+use crate::errors::MieError;
+use crate::video::DownloadedVideo;
+
+// Only look at the start of the video, bars don't usually change halfway through
+const ANALYSE_SECONDS: &str = "120";
+// Ignore crops that only shave off a couple of pixels
+const MIN_REMOVED_RATIO: f32 = 0.04;
+// If almost everything would be removed the video is probably just dark
+const MIN_KEPT_RATIO: f32 = 0.15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CropArea {
+    pub width: u32,
+    pub height: u32,
+    pub x: u32,
+    pub y: u32,
+}
+
+impl CropArea {
+    fn area(&self) -> u64 {
+        self.width as u64 * self.height as u64
+    }
+}
+
+#[derive(Debug)]
+pub enum CropResult {
+    NotNeeded,
+    Cropped {
+        path: String,
+        source: (u32, u32),
+        area: CropArea,
+        crop_time: u128,
+    },
+}
+
+impl CropResult {
+    pub fn describe(&self) -> String {
+        match self {
+            CropResult::NotNeeded => "Not needed".to_string(),
+            CropResult::Cropped {
+                source,
+                area,
+                crop_time,
+                ..
+            } => format!(
+                "{}x{} → {}x{} ({}ms)",
+                source.0, source.1, area.width, area.height, crop_time
+            ),
+        }
+    }
+}
+
+/// Detects black bars or plain borders around the video and crops them off
+/// into a new file next to the original.
+pub async fn crop_video(video: &DownloadedVideo) -> Result<CropResult, MieError> {
+    let process_start = Instant::now();
+    let (source_width, source_height) = probe_dimensions(&video.path).await?;
+
+    // Run cropdetect once for black bars and once on the negated video, which
+    // catches the white borders of meme templates
+    let black = detect_crop(&video.path, "cropdetect=limit=24:round=2:reset=0").await?;
+    let white = detect_crop(&video.path, "negate,cropdetect=limit=24:round=2:reset=0").await?;
+
+    let Some(area) = smaller_area(black, white) else {
+        return Ok(CropResult::NotNeeded);
+    };
+
+    let removed_width = 1.0 - area.width as f32 / source_width as f32;
+    let removed_height = 1.0 - area.height as f32 / source_height as f32;
+    let kept = area.area() as f32 / (source_width as f32 * source_height as f32);
+
+    tracing::debug!(
+        video.path,
+        removed_width,
+        removed_height,
+        kept,
+        "crop detected {:?}",
+        area
+    );
+
+    if (removed_width < MIN_REMOVED_RATIO && removed_height < MIN_REMOVED_RATIO)
+        || kept < MIN_KEPT_RATIO
+    {
+        return Ok(CropResult::NotNeeded);
+    }
+
+    let path = video
+        .workspace
+        .path(&format!("{}_cropped.mp4", video.downloaded_file_name));
+
+    let output = Command::new("ffmpeg")
+        .args(["-y", "-v", "error", "-i", &video.path, "-vf"])
+        .arg(format!(
+            "crop={}:{}:{}:{}",
+            area.width, area.height, area.x, area.y
+        ))
+        .args(["-c:a", "copy", "-movflags", "+faststart", &path])
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|err| MieError::FfmpegError(err.to_string()))?;
+
+    if !output.status.success() || !Path::new(&path).is_file() {
+        return Err(MieError::FfmpegError(
+            String::from_utf8_lossy(&output.stderr).to_string(),
+        ));
+    }
+
+    let crop_time = process_start.elapsed().as_millis();
+    tracing::info!(video.path, "Cropping took {}ms", crop_time);
+
+    Ok(CropResult::Cropped {
+        path,
+        source: (source_width, source_height),
+        area,
+        crop_time,
+    })
+}
+
+/// Width and height of the first video stream, `path` can also be a url.
+pub async fn probe_dimensions(path: &str) -> Result<(u32, u32), MieError> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-select_streams",
+            "v:0",
+            "-show_entries",
+            "stream=width,height",
+            "-of",
+            "csv=p=0:s=x",
+            path,
+        ])
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|err| MieError::FfmpegError(err.to_string()))?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let dimensions = stdout
+        .trim()
+        .split_once('x')
+        .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)));
+
+    match dimensions {
+        Some((width, height)) if width > 0 && height > 0 => Ok((width, height)),
+        _ => Err(MieError::FfmpegError(format!(
+            "could not read video dimensions: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ))),
+    }
+}
+
+async fn detect_crop(path: &str, filter: &str) -> Result<Option<CropArea>, MieError> {
+    let output = Command::new("ffmpeg")
+        .args(["-hide_banner", "-t", ANALYSE_SECONDS, "-i", path, "-vf"])
+        .arg(filter)
+        .args(["-an", "-f", "null", "-"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|err| MieError::FfmpegError(err.to_string()))?;
+
+    if !output.status.success() {
+        return Err(MieError::FfmpegError(
+            String::from_utf8_lossy(&output.stderr).to_string(),
+        ));
+    }
+
+    // With reset=0 every line holds the union of all frames so far,
+    // meaning the last one covers the whole analysed section
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    Ok(stderr
+        .lines()
+        .rev()
+        .find_map(|line| line.rsplit_once("crop=").map(|(_, crop)| crop))
+        .and_then(parse_crop))
+}
+
+/// The crop that removes more, either one when only one found something.
+fn smaller_area(black: Option<CropArea>, white: Option<CropArea>) -> Option<CropArea> {
+    match (black, white) {
+        (Some(black), Some(white)) => Some(if black.area() <= white.area() {
+            black
+        } else {
+            white
+        }),
+        (black, white) => black.or(white),
+    }
+}
+
+fn parse_crop(value: &str) -> Option<CropArea> {
+    let mut parts = value.trim().split(':').map(|p| p.parse::<i64>().ok());
+    let width = parts.next()??;
+    let height = parts.next()??;
+    let x = parts.next()??;
+    let y = parts.next()??;
+
+    // cropdetect reports negative sizes when it never saw a non-black pixel
+    if width <= 0 || height <= 0 || x < 0 || y < 0 {
+        return None;
+    }
+
+    Some(CropArea {
+        width: width as u32,
+        height: height as u32,
+        x: x as u32,
+        y: y as u32,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn area(width: u32, height: u32) -> CropArea {
+        CropArea {
+            width,
+            height,
+            x: 0,
+            y: 0,
+        }
+    }
+
+    #[test]
+    fn parses_cropdetect_output() {
+        assert_eq!(
+            parse_crop("1280:528:0:96"),
+            Some(CropArea {
+                width: 1280,
+                height: 528,
+                x: 0,
+                y: 96,
+            })
+        );
+        assert_eq!(
+            parse_crop(" 640:360:2:4\n"),
+            Some(CropArea {
+                width: 640,
+                height: 360,
+                x: 2,
+                y: 4
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_empty_or_broken_crops() {
+        // What cropdetect prints for a video that's black the whole way through
+        assert_eq!(parse_crop("-1264:-704:1270:710"), None);
+        assert_eq!(parse_crop("0:360:0:0"), None);
+        assert_eq!(parse_crop("1280:720:0"), None);
+        assert_eq!(parse_crop("1280:abc:0:0"), None);
+        assert_eq!(parse_crop(""), None);
+    }
+
+    #[test]
+    fn picks_the_smaller_area() {
+        let black = area(1280, 528);
+        let white = area(1000, 500);
+        assert_eq!(smaller_area(Some(black), Some(white)), Some(white));
+        assert_eq!(smaller_area(Some(white), Some(black)), Some(white));
+        assert_eq!(smaller_area(Some(black), None), Some(black));
+        assert_eq!(smaller_area(None, Some(white)), Some(white));
+        assert_eq!(smaller_area(None, None), None);
+    }
+
+    #[test]
+    fn ties_go_to_black_bars() {
+        let black = CropArea {
+            x: 10,
+            ..area(100, 100)
+        };
+        let white = CropArea {
+            y: 10,
+            ..area(100, 100)
+        };
+        assert_eq!(smaller_area(Some(black), Some(white)), Some(black));
+    }
+}
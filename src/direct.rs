@@ -0,0 +1,161 @@
+use std::io::SeekFrom;
+use std::path::Path;
+
+use reqwest::header::{CONTENT_LENGTH, CONTENT_TYPE, RANGE};
+use reqwest::{Client, StatusCode};
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use url::Url;
+
+use crate::errors::MieError;
+
+const VIDEO_EXTENSIONS: [&str; 4] = ["mp4", "webm", "mov", "m4v"];
+const MAX_RESUMES: u32 = 3;
+
+#[derive(Debug)]
+pub struct DirectFile {
+    pub extension: String,
+    pub size: Option<u64>,
+}
+
+fn video_extension(url: &Url) -> Option<String> {
+    Path::new(url.path())
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .filter(|ext| VIDEO_EXTENSIONS.contains(&ext.as_str()))
+}
+
+/// Cheap check from the url alone, used to skip anything yt-dlp related.
+pub fn is_direct_link(url: &Url) -> bool {
+    video_extension(url).is_some()
+}
+
+/// Checks if the url points straight at a video file, either from the
+/// extension in the path or the content type the server reports.
+pub async fn probe_direct(client: &Client, url: &Url) -> Option<DirectFile> {
+    let path_extension = video_extension(url);
+
+    let head = client.head(url.as_str()).send().await.ok();
+    let head = head.filter(|response| response.status().is_success());
+
+    let content_type = head
+        .as_ref()
+        .and_then(|response| response.headers().get(CONTENT_TYPE))
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_lowercase());
+
+    let size = head
+        .as_ref()
+        .and_then(|response| response.headers().get(CONTENT_LENGTH))
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+
+    let extension = match (path_extension, content_type.as_deref()) {
+        (Some(extension), _) => extension,
+        (None, Some(content_type)) if content_type.starts_with("video/") => {
+            extension_for(content_type).to_string()
+        }
+        _ => return None,
+    };
+
+    Some(DirectFile { extension, size })
+}
+
+/// Streams the file to `path`, picking up where it left off with a range
+/// request if the connection drops halfway.
+pub async fn download_direct(
+    client: &Client,
+    url: &Url,
+    path: &str,
+    max_size: u64,
+) -> Result<u64, MieError> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(path)
+        .await
+        .map_err(|err| MieError::DirectDownloadFailed(err.to_string()))?;
+
+    let mut written: u64 = 0;
+    let mut resumes = 0;
+
+    loop {
+        let mut request = client.get(url.as_str());
+        if written > 0 {
+            request = request.header(RANGE, format!("bytes={}-", written));
+        }
+
+        let mut response = request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| MieError::DirectDownloadFailed(err.to_string()))?;
+
+        // Server ignored the range, start over
+        if written > 0 && response.status() != StatusCode::PARTIAL_CONTENT {
+            tracing::debug!(url = url.as_str(), "range not supported, restarting");
+            file.set_len(0)
+                .await
+                .map_err(|err| MieError::DirectDownloadFailed(err.to_string()))?;
+            file.seek(SeekFrom::Start(0))
+                .await
+                .map_err(|err| MieError::DirectDownloadFailed(err.to_string()))?;
+            written = 0;
+        }
+
+        if let Some(length) = response.content_length() {
+            if written + length > max_size {
+                return Err(MieError::FileTooLarge(written + length));
+            }
+        }
+
+        let result = loop {
+            match response.chunk().await {
+                Ok(Some(chunk)) => {
+                    written += chunk.len() as u64;
+                    if written > max_size {
+                        return Err(MieError::FileTooLarge(written));
+                    }
+
+                    file.write_all(&chunk)
+                        .await
+                        .map_err(|err| MieError::DirectDownloadFailed(err.to_string()))?;
+                }
+                Ok(None) => break Ok(()),
+                Err(err) => break Err(err),
+            }
+        };
+
+        match result {
+            Ok(()) => break,
+            Err(err) if resumes < MAX_RESUMES => {
+                resumes += 1;
+                tracing::warn!(
+                    url = url.as_str(),
+                    written,
+                    "direct download interrupted, resuming ({}/{}): {}",
+                    resumes,
+                    MAX_RESUMES,
+                    err
+                );
+            }
+            Err(err) => return Err(MieError::DirectDownloadFailed(err.to_string())),
+        }
+    }
+
+    file.flush()
+        .await
+        .map_err(|err| MieError::DirectDownloadFailed(err.to_string()))?;
+
+    Ok(written)
+}
+
+fn extension_for(content_type: &str) -> &'static str {
+    match content_type.split(';').next().unwrap_or_default().trim() {
+        "video/webm" => "webm",
+        "video/quicktime" => "mov",
+        "video/x-m4v" => "m4v",
+        _ => "mp4",
+    }
+}
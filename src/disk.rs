@@ -0,0 +1,126 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use crate::env::Config;
+use crate::errors::MieError;
+
+// Used when the probe couldn't tell how big the download will be
+const DEFAULT_ESTIMATE: u64 = 50_000_000;
+const RECHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy)]
+pub struct DiskUsage {
+    pub total: u64,
+    pub available: u64,
+}
+
+pub fn disk_usage(path: &str) -> Result<DiskUsage, MieError> {
+    let stats = fs4::statvfs(path).map_err(|err| MieError::WorkspaceError(err.to_string()))?;
+
+    Ok(DiskUsage {
+        total: stats.total_space(),
+        available: stats.available_space(),
+    })
+}
+
+/// Keeps downloads from filling the work dir, jobs reserve the space they
+/// expect to use and wait in line while there isn't enough of it.
+#[derive(Default)]
+pub struct DiskScheduler {
+    reserved: Arc<Mutex<u64>>,
+    released: Arc<Notify>,
+    queued: AtomicUsize,
+}
+
+/// Space held for a running download, given back when dropped.
+pub struct Reservation {
+    size: u64,
+    reserved: Arc<Mutex<u64>>,
+    released: Arc<Notify>,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        *self.reserved.lock().unwrap() -= self.size;
+        self.released.notify_waiters();
+    }
+}
+
+/// Counts a job as waiting in line for as long as it's held, so the count
+/// goes back down however the wait ends, dropped futures included.
+struct Queued<'a>(&'a AtomicUsize);
+
+impl<'a> Queued<'a> {
+    fn enter(queued: &'a AtomicUsize) -> Self {
+        queued.fetch_add(1, Ordering::Relaxed);
+        Queued(queued)
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl DiskScheduler {
+    pub fn reserved(&self) -> u64 {
+        *self.reserved.lock().unwrap()
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    pub async fn reserve(
+        &self,
+        config: &Config,
+        estimated_size: Option<u64>,
+    ) -> Result<Reservation, MieError> {
+        let size = estimated_size.unwrap_or(DEFAULT_ESTIMATE);
+        let deadline = Instant::now() + Duration::from_secs(config.disk_queue_timeout);
+
+        let _queued = Queued::enter(&self.queued);
+        loop {
+            let usage = disk_usage(&config.work_dir)?;
+
+            // Would never fit, even with nothing else running
+            if size + config.disk_min_free > usage.total {
+                return Err(MieError::InsufficientDiskSpace(size));
+            }
+
+            // Register before checking so a release in between isn't missed
+            let released = self.released.notified();
+
+            {
+                let mut reserved = self.reserved.lock().unwrap();
+                if usage.available >= *reserved + size + config.disk_min_free {
+                    *reserved += size;
+                    return Ok(Reservation {
+                        size,
+                        reserved: self.reserved.clone(),
+                        released: self.released.clone(),
+                    });
+                }
+            }
+
+            if Instant::now() >= deadline {
+                return Err(MieError::InsufficientDiskSpace(size));
+            }
+
+            tracing::info!(
+                size,
+                available = usage.available,
+                "not enough disk space, waiting"
+            );
+
+            // Files from other jobs also get removed without a reservation
+            // being released, so check again every now and then
+            let _ = tokio::time::timeout(RECHECK_INTERVAL, released).await;
+        }
+    }
+}
@@ -1,36 +1,120 @@
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::Result;
-use twilight_model::channel::message::embed::EmbedField;
-use twilight_model::channel::message::Embed;
+use twilight_model::channel::message::embed::{EmbedField, EmbedImage};
+use twilight_model::channel::message::{Component, Embed};
 use twilight_model::channel::Message;
-use twilight_model::id::marker::{ChannelMarker, MessageMarker};
+use twilight_model::id::marker::{ChannelMarker, InteractionMarker, MessageMarker};
 use twilight_model::id::Id;
 
+use crate::metrics;
 use crate::AppContext;
 
+// Interaction tokens last 15 minutes, leave some room for slow requests
+const TOKEN_LIFETIME: Duration = Duration::from_secs(14 * 60);
+// Milliseconds since the unix epoch discord's snowflakes count from
+const DISCORD_EPOCH: u64 = 1_420_070_400_000;
+
 pub struct MieEmbed {
     embed: Embed,
+    gallery: Vec<Embed>,
+    components: Vec<Component>,
     ctx: Arc<AppContext>,
     message_id: Option<Id<MessageMarker>>,
     channel_id: Id<ChannelMarker>,
+    // Sent as a followup to this interaction, and when discord created it
+    interaction: Option<(String, SystemTime)>,
 }
 
 impl MieEmbed {
     pub fn new(ctx: Arc<AppContext>, channel_id: Id<ChannelMarker>) -> Self {
         MieEmbed {
             embed: Self::default_embed(),
+            gallery: vec![],
+            components: vec![],
             ctx,
             message_id: None,
             channel_id,
+            interaction: None,
+        }
+    }
+
+    /// Posts as a followup to a slash command, for when the bot might not be
+    /// allowed to send messages in the channel itself. The token's lifetime
+    /// counts from the interaction's id, not from when this gets made.
+    pub fn followup(
+        ctx: Arc<AppContext>,
+        channel_id: Id<ChannelMarker>,
+        interaction_id: Id<InteractionMarker>,
+        interaction_token: String,
+    ) -> Self {
+        let created =
+            UNIX_EPOCH + Duration::from_millis((interaction_id.get() >> 22) + DISCORD_EPOCH);
+        MieEmbed {
+            interaction: Some((interaction_token, created)),
+            ..Self::new(ctx, channel_id)
+        }
+    }
+
+    /// Takes over a message sent earlier, the first update replaces it.
+    pub fn existing(
+        ctx: Arc<AppContext>,
+        channel_id: Id<ChannelMarker>,
+        message_id: Id<MessageMarker>,
+    ) -> Self {
+        MieEmbed {
+            message_id: Some(message_id),
+            ..Self::new(ctx, channel_id)
         }
     }
 
+    pub fn channel_id(&self) -> Id<ChannelMarker> {
+        self.channel_id
+    }
+
+    pub fn message_id(&self) -> Option<Id<MessageMarker>> {
+        self.message_id
+    }
+
     pub fn title(&mut self, title: String) -> &mut Self {
         self.embed.title = Some(title);
         self
     }
 
+    pub fn description(&mut self, description: String) -> &mut Self {
+        self.embed.description = Some(description);
+        self
+    }
+
+    /// Shows up to 4 images as a gallery, discord merges embeds that share
+    /// the same url into a single one.
+    pub fn images(&mut self, url: String, images: Vec<String>) -> &mut Self {
+        let mut images = images.into_iter().map(|image| EmbedImage {
+            height: None,
+            proxy_url: None,
+            url: image,
+            width: None,
+        });
+
+        self.embed.url = Some(url.clone());
+        self.embed.image = images.next();
+        self.gallery = images
+            .take(3)
+            .map(|image| Embed {
+                url: Some(url.clone()),
+                image: Some(image),
+                ..Self::default_embed()
+            })
+            .collect();
+        self
+    }
+
+    pub fn components(&mut self, components: Vec<Component>) -> &mut Self {
+        self.components = components;
+        self
+    }
+
     pub fn add_field(&mut self, field: EmbedField) -> &mut Self {
         self.embed.fields.push(field);
         self
@@ -54,16 +138,65 @@ impl MieEmbed {
     }
 
     pub async fn send_or_update(&mut self) -> Result<Message> {
-        if self.message_id.is_some() {
+        let result = self.send_or_update_inner().await;
+        if result.is_err() {
+            metrics::discord_error("message");
+        }
+        result
+    }
+
+    async fn send_or_update_inner(&mut self) -> Result<Message> {
+        // Followups outlive their token on long jobs, the channel endpoint
+        // takes over from there
+        let token = self
+            .interaction
+            .as_ref()
+            .filter(|(_, created)| {
+                SystemTime::now()
+                    .duration_since(*created)
+                    .unwrap_or_default()
+                    < TOKEN_LIFETIME
+            })
+            .map(|(token, _)| token.as_str());
+
+        if let Some(token) = token {
+            let client = self.ctx.http.interaction(self.ctx.application_id);
+            let message = match self.message_id {
+                Some(message_id) => {
+                    client
+                        .update_followup(token, message_id)
+                        .embeds(Some(&self.build_all()))?
+                        .components(Some(&self.components))?
+                        .await?
+                        .model()
+                        .await?
+                }
+                None => {
+                    client
+                        .create_followup(token)
+                        .embeds(&self.build_all())?
+                        .components(&self.components)?
+                        .await?
+                        .model()
+                        .await?
+                }
+            };
+
+            self.message_id = Some(message.id);
+            return Ok(message);
+        }
+
+        if let Some(message_id) = self.message_id {
             tracing::debug!(
-                message_id = self.message_id.unwrap().to_string(),
+                message_id = message_id.to_string(),
                 "have message_id, updating existing embed"
             );
             let result = self
                 .ctx
                 .http
-                .update_message(self.channel_id, self.message_id.unwrap())
-                .embeds(Some(&[self.embed.clone()]))?
+                .update_message(self.channel_id, message_id)
+                .embeds(Some(&self.build_all()))?
+                .components(Some(&self.components))?
                 .await?
                 .model()
                 .await?;
@@ -76,7 +209,8 @@ impl MieEmbed {
             .ctx
             .http
             .create_message(self.channel_id)
-            .embeds(&[self.embed.clone()])?
+            .embeds(&self.build_all())?
+            .components(&self.components)?
             .await?
             .model()
             .await?;
@@ -89,6 +223,12 @@ impl MieEmbed {
         self.embed.clone()
     }
 
+    pub fn build_all(&self) -> Vec<Embed> {
+        let mut embeds = vec![self.embed.clone()];
+        embeds.extend(self.gallery.iter().cloned());
+        embeds
+    }
+
     fn default_embed() -> Embed {
         Embed {
             author: None,
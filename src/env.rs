@@ -1,6 +1,9 @@
 use std::env;
+use std::net::{IpAddr, SocketAddr};
 
 use dotenvy::Error as DotEnvError;
+use twilight_model::id::marker::UserMarker;
+use twilight_model::id::Id;
 
 #[derive(Clone, Debug)]
 pub struct Config {
@@ -10,6 +13,67 @@ pub struct Config {
     pub b2_application_key: String,
     pub b2_bucket_path_prefix: String,
     pub b2_bucket_id: String,
+    pub work_dir: String,
+    pub playlist_max_items: usize,
+    pub max_download_size: u64,
+    // Seconds
+    pub max_duration: u64,
+    pub allow_live_streams: bool,
+    // Bytes to always leave free in the work dir
+    pub disk_min_free: u64,
+    // Seconds a job waits for disk space before giving up
+    pub disk_queue_timeout: u64,
+    // Folder of netscape cookie files named after their domain
+    pub cookies_dir: Option<String>,
+    pub netrc_file: Option<String>,
+    // Users allowed to run admin only commands
+    pub admin_ids: Vec<Id<UserMarker>>,
+    // Used for every download unless the domain has its own
+    pub proxy: Option<String>,
+    // Pairs of domain and proxy, e.g. `instagram.com=socks5://host:1080`
+    pub domain_proxies: Vec<(String, String)>,
+    // Tried in order when a site rate limits or geo blocks us
+    pub fallback_proxies: Vec<String>,
+    // Local address to send requests from, picks the network interface
+    pub source_address: Option<IpAddr>,
+    pub cache_path: String,
+    // Seconds a download result is reused for
+    pub cache_ttl: u64,
+    pub media_path: String,
+    // Uploads older than this are deleted unless their message is pinned
+    pub retention_days: Option<u64>,
+    // Bytes each guild keeps, the oldest uploads go first
+    pub retention_guild_bytes: Option<u64>,
+    // Seconds between retention sweeps
+    pub retention_interval: u64,
+    // Bytes a guild or a single user can have stored before uploads are refused
+    pub guild_quota: Option<u64>,
+    pub user_quota: Option<u64>,
+    // Sign links with a download authorization, for private buckets
+    pub private_links: bool,
+    // Seconds a signed link works for, b2 allows up to a week
+    pub link_lifetime: u64,
+    // Address for the media server, it doesn't run when unset
+    pub http_addr: Option<SocketAddr>,
+    // Address for /metrics and the health checks, kept off the media server
+    // since anyone can reach that
+    pub metrics_addr: SocketAddr,
+    // Where the media server can be reached, links go to its pages when set
+    pub public_url: Option<String>,
+    // Key the media server signs its private links with, falls back to the
+    // b2 key so links keep working across restarts
+    pub link_secret: String,
+    // Pairs of api token and the user its jobs run as, e.g. `secret=1234`
+    pub api_tokens: Vec<(String, Id<UserMarker>)>,
+    // OAuth2 secret of the bot's application, the web gallery needs it to log people in
+    pub discord_client_secret: Option<String>,
+    // Seconds the gateway can go without sending anything before we exit
+    pub gateway_timeout: u64,
+    // Seconds each stage of a job gets before it's cancelled
+    pub probe_timeout: u64,
+    pub download_timeout: u64,
+    pub transcode_timeout: u64,
+    pub upload_timeout: u64,
 }
 
 pub fn load_env() -> Result<(), anyhow::Error> {
@@ -31,5 +95,155 @@ pub fn create_config() -> Config {
             .expect("No B2_BUCKET_PATH_PREFIX provided"),
 
         b2_bucket_id: env::var("B2_BUCKET_ID").expect("No B2_BUCKET_ID provided"),
+        work_dir: env::var("WORK_DIR").unwrap_or_else(|_| "/tmp/mie".to_string()),
+        playlist_max_items: env::var("PLAYLIST_MAX_ITEMS")
+            .ok()
+            .and_then(|max| max.parse().ok())
+            .unwrap_or(10),
+        max_download_size: env::var("MAX_DOWNLOAD_SIZE")
+            .ok()
+            .and_then(|max| max.parse().ok())
+            .unwrap_or(500_000_000),
+        max_duration: env::var("MAX_DURATION")
+            .ok()
+            .and_then(|max| max.parse().ok())
+            .unwrap_or(30 * 60),
+        allow_live_streams: env::var("ALLOW_LIVE_STREAMS").is_ok_and(|allow| allow == "true"),
+        disk_min_free: env::var("DISK_MIN_FREE")
+            .ok()
+            .and_then(|min| min.parse().ok())
+            .unwrap_or(1_000_000_000),
+        disk_queue_timeout: env::var("DISK_QUEUE_TIMEOUT")
+            .ok()
+            .and_then(|timeout| timeout.parse().ok())
+            .unwrap_or(10 * 60),
+        cookies_dir: env::var("COOKIES_DIR").ok(),
+        netrc_file: env::var("NETRC_FILE").ok(),
+        admin_ids: env::var("ADMIN_IDS")
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|id| id.trim().parse().ok())
+            .collect(),
+        proxy: env::var("PROXY").ok(),
+        domain_proxies: env::var("DOMAIN_PROXIES")
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(domain, proxy)| (domain.trim().to_lowercase(), proxy.trim().to_string()))
+            .collect(),
+        fallback_proxies: env::var("FALLBACK_PROXIES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|proxy| !proxy.is_empty())
+            .map(str::to_string)
+            .collect(),
+        source_address: env::var("SOURCE_ADDRESS").ok().and_then(|v| v.parse().ok()),
+        cache_path: env::var("CACHE_PATH").unwrap_or("mie-cache.redb".to_string()),
+        cache_ttl: env::var("CACHE_TTL")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(7 * 24 * 60 * 60),
+        media_path: env::var("MEDIA_PATH").unwrap_or("mie-media.redb".to_string()),
+        retention_days: env::var("RETENTION_DAYS").ok().and_then(|v| v.parse().ok()),
+        retention_guild_bytes: env::var("RETENTION_GUILD_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok()),
+        retention_interval: env::var("RETENTION_INTERVAL")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(60 * 60),
+        guild_quota: env::var("GUILD_QUOTA").ok().and_then(|v| v.parse().ok()),
+        user_quota: env::var("USER_QUOTA").ok().and_then(|v| v.parse().ok()),
+        private_links: env::var("PRIVATE_LINKS").is_ok_and(|private| private == "true"),
+        link_lifetime: env::var("LINK_LIFETIME")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(7 * 24 * 60 * 60),
+        http_addr: env::var("HTTP_ADDR").ok().and_then(|v| v.parse().ok()),
+        metrics_addr: env::var("METRICS_ADDR")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(SocketAddr::from(([127, 0, 0, 1], 9091))),
+        public_url: env::var("PUBLIC_URL").ok(),
+        link_secret: env::var("LINK_SECRET")
+            .or_else(|_| env::var("B2_APPLICATION_KEY"))
+            .expect("No LINK_SECRET provided"),
+        api_tokens: env::var("API_TOKENS")
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|pair| pair.split_once('='))
+            .filter_map(|(token, id)| Some((token.trim().to_string(), id.trim().parse().ok()?)))
+            .filter(|(token, _)| !token.is_empty())
+            .collect(),
+        discord_client_secret: env::var("DISCORD_CLIENT_SECRET").ok(),
+        gateway_timeout: env::var("GATEWAY_TIMEOUT")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(5 * 60),
+        probe_timeout: env::var("PROBE_TIMEOUT")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(60),
+        download_timeout: env::var("DOWNLOAD_TIMEOUT")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(15 * 60),
+        transcode_timeout: env::var("TRANSCODE_TIMEOUT")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(10 * 60),
+        upload_timeout: env::var("UPLOAD_TIMEOUT")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(15 * 60),
+    }
+}
+
+/// Everything optional left off, for tests that only care about a few fields.
+#[cfg(test)]
+pub fn test_config() -> Config {
+    Config {
+        discord_token: String::new(),
+        cdn_url: "https://cdn.example.com".to_string(),
+        b2_key_id: String::new(),
+        b2_application_key: String::new(),
+        b2_bucket_path_prefix: "mie".to_string(),
+        b2_bucket_id: String::new(),
+        work_dir: "/tmp/mie".to_string(),
+        playlist_max_items: 25,
+        max_download_size: 500_000_000,
+        max_duration: 3 * 60 * 60,
+        allow_live_streams: false,
+        disk_min_free: 0,
+        disk_queue_timeout: 0,
+        cookies_dir: None,
+        netrc_file: None,
+        admin_ids: vec![],
+        proxy: None,
+        domain_proxies: vec![],
+        fallback_proxies: vec![],
+        source_address: None,
+        cache_path: String::new(),
+        cache_ttl: 0,
+        media_path: String::new(),
+        retention_days: None,
+        retention_guild_bytes: None,
+        retention_interval: 0,
+        guild_quota: None,
+        user_quota: None,
+        private_links: false,
+        link_lifetime: 7 * 24 * 60 * 60,
+        http_addr: None,
+        metrics_addr: SocketAddr::from(([127, 0, 0, 1], 9091)),
+        public_url: None,
+        link_secret: "secret".to_string(),
+        api_tokens: vec![],
+        discord_client_secret: None,
+        gateway_timeout: 0,
+        probe_timeout: 0,
+        download_timeout: 0,
+        transcode_timeout: 0,
+        upload_timeout: 0,
     }
 }
@@ -1,14 +1,29 @@
 use std::error::Error;
 use std::fmt::{Display, Formatter, Result};
 
-use ytd_rs::error::YoutubeDLError;
-
+use crate::stage::Stage;
 use crate::video::DownloadedVideo;
 
 #[derive(Debug)]
 pub enum MieError {
-    VideoDownloadFailed(DownloadedVideo),
-    YtDlError(YoutubeDLError),
+    VideoDownloadFailed(Box<DownloadedVideo>),
+    YtDlError(String),
+    FfmpegError(String),
+    ProbeFailed(String),
+    DirectDownloadFailed(String),
+    FileTooLarge(u64),
+    TooLong(f64),
+    LiveStream,
+    WorkspaceError(String),
+    InsufficientDiskSpace(u64),
+    CookieError(String),
+    ProxyError(String),
+    // Stage that ran out of time and its timeout in seconds
+    TimedOut(Stage, u64),
+    Cancelled,
+    // Quota in bytes
+    GuildQuotaExceeded(u64),
+    UserQuotaExceeded(u64),
 }
 
 impl Error for MieError {}
@@ -19,9 +34,58 @@ impl Display for MieError {
             MieError::VideoDownloadFailed(video) => {
                 write!(f, "failed to download video: {}", video.og_url)
             }
-            MieError::YtDlError(ytdl_erro) => {
-                // todo: make this better not sure how
-                write!(f, "ytdl error: {}", ytdl_erro.to_string())
+            MieError::YtDlError(err) => {
+                write!(f, "ytdl error: {}", err)
+            }
+            MieError::FfmpegError(err) => {
+                write!(f, "ffmpeg error: {}", err)
+            }
+            MieError::ProbeFailed(err) => {
+                write!(f, "failed to get video info: {}", err)
+            }
+            MieError::DirectDownloadFailed(err) => {
+                write!(f, "failed to download file: {}", err)
+            }
+            MieError::FileTooLarge(size) => {
+                write!(f, "file is too large: {:.1} MB", *size as f64 / 1_000_000.0)
+            }
+            MieError::TooLong(duration) => {
+                write!(f, "video is too long: {:.0} minutes", duration / 60.0)
+            }
+            MieError::LiveStream => {
+                write!(f, "live streams can't be downloaded")
+            }
+            MieError::WorkspaceError(err) => {
+                write!(f, "failed to set up working directory: {}", err)
+            }
+            MieError::InsufficientDiskSpace(size) => {
+                write!(
+                    f,
+                    "not enough disk space for a {:.1} MB download",
+                    *size as f64 / 1_000_000.0
+                )
+            }
+            MieError::CookieError(err) => {
+                write!(f, "failed to update cookies: {}", err)
+            }
+            MieError::ProxyError(err) => write!(f, "invalid proxy: {}", err),
+            MieError::TimedOut(stage, seconds) => {
+                write!(f, "{} timed out after {}s", stage, seconds)
+            }
+            MieError::Cancelled => write!(f, "cancelled"),
+            MieError::GuildQuotaExceeded(quota) => {
+                write!(
+                    f,
+                    "this server has used up its {:.1} GB of storage",
+                    *quota as f64 / 1_000_000_000.0
+                )
+            }
+            MieError::UserQuotaExceeded(quota) => {
+                write!(
+                    f,
+                    "you have used up your {:.1} GB of storage",
+                    *quota as f64 / 1_000_000_000.0
+                )
             }
         }
     }
@@ -1,11 +1,22 @@
 use std::sync::Arc;
-use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
 use twilight_model::channel::message::embed::EmbedField;
 use twilight_model::gateway::payload::incoming::MessageCreate;
 
+use crate::cache::{cache_key, show_cached};
+use crate::crop::{crop_video, CropResult};
 use crate::embed::MieEmbed;
-use crate::upload::{self, upload_files};
-use crate::video::download_video;
+use crate::errors::MieError;
+use crate::links::refresh_components;
+use crate::media::MediaOrigin;
+use crate::metrics::{job_finished, Outcome};
+use crate::playlist::mirror_playlist;
+use crate::probe::{plan_download, DownloadPlan};
+use crate::quota::check_quota;
+use crate::retry::Backoff;
+use crate::stage::{download_with_retry, run_stage, upload_video, Progress, Stage};
+use crate::upload::UploadBatch;
+use crate::video::Quality;
 use crate::AppContext;
 use url::Url;
 
@@ -25,9 +36,13 @@ async fn handle_message_create_inner(
 ) -> anyhow::Result<()> {
     for word in event.content.split_whitespace() {
         let is_http = word.starts_with("https://") || word.starts_with("http://");
-        let is_cdn = !DEBUG && word.starts_with(&ctx.config.cdn_url);
+        let is_cdn = !DEBUG
+            && (word.starts_with(&ctx.config.cdn_url)
+                || ctx.config.public_url.as_ref().is_some_and(|public_url| {
+                    word.starts_with(&format!("{}/", public_url.trim_end_matches('/')))
+                }));
         // Ignore if word is not a potential link or the link
-        // is from the cdn url we use
+        // is from the cdn url or media server we use
         if !is_http || is_cdn {
             tracing::trace!(is_http = is_http, is_cdn = is_cdn, "ignore word {}", word);
             continue;
@@ -36,6 +51,31 @@ async fn handle_message_create_inner(
         let video_url = Url::parse(word)?;
         let mut embed = MieEmbed::new(ctx.clone(), event.channel_id);
 
+        // Same post shared again, reuse the links from last time
+        let cache_key = cache_key(&ctx.config, &video_url, event.guild_id, event.author.id).await;
+        let cached = ctx
+            .cache
+            .get_signed(&cache_key, &ctx.config, &ctx.b2.basic_client())
+            .await;
+        if let Some(cached) = cached {
+            tracing::info!(word, cache_key, "using cached result");
+            job_finished(word, Outcome::Cached);
+            show_cached(&mut embed, &cached)
+                .components(refresh_components(&ctx.config))
+                .send_or_update()
+                .await?;
+
+            // The files are recorded against the message they were first posted in
+            if let Some(message_id) = embed.message_id() {
+                ctx.media
+                    .attach(event.channel_id, message_id, &cached.all_files());
+            }
+            continue;
+        }
+
+        // Cancelled as soon as any stage runs out of time
+        let cancel = CancellationToken::new();
+
         // Let user know we are downloading their URL
         // also ensures we have permissions to send messages in this channel
         embed
@@ -43,7 +83,89 @@ async fn handle_message_create_inner(
             .send_or_update()
             .await?;
 
-        let downloaded_video = download_video(&video_url.to_string()).await?;
+        let plan = run_stage(
+            &ctx.config,
+            Stage::Probe,
+            &cancel,
+            plan_download(&ctx.config, &video_url),
+        )
+        .await;
+
+        let (quality, estimated_size) = match plan {
+            Ok(DownloadPlan::Playlist(playlist)) => {
+                mirror_playlist(
+                    ctx.clone(),
+                    &mut embed,
+                    playlist,
+                    event.author.id,
+                    event.guild_id,
+                )
+                .await?;
+                continue;
+            }
+            Ok(DownloadPlan::Download(quality, estimated_size)) => (quality, estimated_size),
+            Err(err @ MieError::TimedOut(..)) => {
+                job_finished(word, Outcome::Failed);
+                embed
+                    .title(format!("Failed to download: {}", err))
+                    .send_or_update()
+                    .await?;
+                continue;
+            }
+            Err(err) => {
+                job_finished(word, Outcome::Refused);
+                embed
+                    .title(format!("Refusing to download: {}", err))
+                    .send_or_update()
+                    .await?;
+                continue;
+            }
+        };
+
+        if quality == Quality::Low {
+            embed
+                .title("Too large at best quality, downloading 480p".to_string())
+                .send_or_update()
+                .await?;
+        }
+
+        let downloaded_video = download_with_retry(
+            &ctx,
+            video_url.as_str(),
+            quality,
+            estimated_size,
+            &cancel,
+            &mut EmbedProgress(&mut embed),
+        )
+        .await;
+
+        let downloaded_video = match downloaded_video {
+            Ok(downloaded_video) => downloaded_video,
+            Err(err) => {
+                tracing::error!(word, "failed to download video: {:?}", err);
+                job_finished(word, Outcome::Failed);
+                embed
+                    .title(format!("Failed to download: {}", err))
+                    .send_or_update()
+                    .await?;
+                continue;
+            }
+        };
+
+        let quota = check_quota(
+            &ctx,
+            event.guild_id,
+            event.author.id,
+            downloaded_video.size(),
+        );
+        if let Err(err) = quota {
+            job_finished(word, Outcome::Refused);
+            embed
+                .title(format!("Refusing to upload: {}", err))
+                .send_or_update()
+                .await?;
+            continue;
+        }
 
         embed
             .title("Video Downloading, uploading original...".to_string())
@@ -57,76 +179,173 @@ async fn handle_message_create_inner(
                 value: "Processing".to_string(),
                 inline: true,
             })
-            // .add_field(EmbedField {
-            //     name: "Crop".to_string(),
-            //     value: "Pending".to_string(),
-            //     inline: true,
-            // })
+            .add_field(EmbedField {
+                name: "Crop".to_string(),
+                value: "Pending".to_string(),
+                inline: true,
+            })
             .send_or_update()
             .await?;
 
-        let files = vec![upload::UploadFile {
-            path: downloaded_video.path.clone(),
-        }];
-
-        let bucket = Arc::new(ctx.config.b2_bucket_id.clone()).as_str().into();
-
-        let upload_start = Instant::now();
-
         tracing::info!(word, "uploading start");
 
-        let uploaded_files = upload_files(
-            ctx.b2.clone(),
-            bucket,
-            files,
-            Some(move |_path: &str, uploaded, total, percentage, bps, eta| {
-                tracing::trace!(uploaded, total, percentage, bps, eta, "uploading")
-                // let write = (uploaded, total, percentage, bps);
-                // set_last_update_data.send(write).ok();
-            }),
+        let mirrored = upload_video(
+            &ctx,
+            &downloaded_video,
+            event.author.id,
+            &cancel,
+            &mut EmbedProgress(&mut embed),
         )
         .await;
 
-        let upload_time = upload_start.elapsed().as_millis();
+        let mirrored = match mirrored {
+            Ok(mirrored) => mirrored,
+            Err(err) => {
+                tracing::error!("failed to upload files: {:?}", err);
+                job_finished(word, Outcome::Failed);
+
+                embed
+                    .title(format!("failed to upload video: {}", err))
+                    .update_field(
+                        1,
+                        EmbedField {
+                            name: "Upload".to_string(),
+                            value: "Error".to_string(),
+                            inline: true,
+                        },
+                    )
+                    .update_field(
+                        2,
+                        EmbedField {
+                            name: "Crop".to_string(),
+                            value: "Cancelled".to_string(),
+                            inline: true,
+                        },
+                    )
+                    .send_or_update()
+                    .await?;
+                return Ok(());
+            }
+        };
 
-        if let Err(err) = uploaded_files {
-            tracing::error!("failed to upload files: {:?}", err);
+        job_finished(word, Outcome::Success);
 
+        let origin = MediaOrigin {
+            guild_id: event.guild_id,
+            channel_id: Some(event.channel_id),
+            message_id: embed.message_id(),
+            uploader_id: event.author.id,
+        };
+        let mut cached = mirrored.save(&ctx, &origin, &downloaded_video, Some(&cache_key));
+
+        let links = &mirrored.links;
+        if downloaded_video.is_gallery() {
+            let mut title = format!("Downloaded {} files", links.len());
+            if mirrored.failed > 0 {
+                title += &format!(", {} failed to upload", mirrored.failed);
+            }
+            embed
+                .title(title)
+                .description(links.join("\n"))
+                .images(links[0].clone(), mirrored.images.clone());
+        } else {
+            embed.title(format!("Download: {}", links[0]));
+        }
+
+        embed
+            .components(refresh_components(&ctx.config))
+            .update_field(
+                1,
+                EmbedField {
+                    name: "Upload".to_string(),
+                    value: format!("{}ms", mirrored.upload_time),
+                    inline: true,
+                },
+            )
+            .update_field(
+                2,
+                EmbedField {
+                    name: "Crop".to_string(),
+                    value: "Processing".to_string(),
+                    inline: true,
+                },
+            )
+            .send_or_update()
+            .await?;
+
+        // Cropping only makes sense for a single video
+        if downloaded_video.is_gallery() || !downloaded_video.primary_is_video() {
             embed
-                .title("failed to upload video".to_string())
-                .update_field(
-                    1,
-                    EmbedField {
-                        name: "Upload".to_string(),
-                        value: "Error".to_string(),
-                        inline: true,
-                    },
-                )
                 .update_field(
                     2,
                     EmbedField {
                         name: "Crop".to_string(),
-                        value: "Cancelled".to_string(),
+                        value: "Skipped".to_string(),
                         inline: true,
                     },
                 )
                 .send_or_update()
                 .await?;
-            return Ok(());
+            continue;
         }
 
-        tracing::info!(word, "uploading complete in {}ms", upload_time);
+        let cropped = run_stage(
+            &ctx.config,
+            Stage::Transcode,
+            &cancel,
+            crop_video(&downloaded_video),
+        )
+        .await;
+
+        let crop_value = match cropped {
+            Ok(CropResult::Cropped { ref path, .. })
+                if check_quota(&ctx, event.guild_id, event.author.id, file_size(path)).is_err() =>
+            {
+                "Quota reached".to_string()
+            }
+            Ok(ref crop @ CropResult::Cropped { ref path, .. }) => {
+                let mut batch =
+                    UploadBatch::new(vec![downloaded_video.upload(path, event.author.id)]);
+                let uploaded_crop = run_stage(
+                    &ctx.config,
+                    Stage::Upload,
+                    &cancel,
+                    batch.upload(&ctx.config, ctx.b2.clone(), &cancel),
+                )
+                .await;
+
+                match uploaded_crop.map(|()| batch.finish().remove(0)) {
+                    Ok(Ok(uploaded)) => {
+                        ctx.media
+                            .record(&origin, &downloaded_video, Some(&cache_key), [&uploaded]);
+                        let link = uploaded.url;
+                        embed.description(format!("Cropped: {}", link));
+
+                        cached.cropped = Some(link);
+                        cached.cropped_file = Some(uploaded.file_name);
+                        ctx.cache.insert(&cache_key, &cached);
+                        crop.describe()
+                    }
+                    Ok(Err(err)) | Err(err) => {
+                        tracing::error!("failed to upload cropped video: {:?}", err);
+                        "Upload error".to_string()
+                    }
+                }
+            }
+            Ok(crop) => crop.describe(),
+            Err(MieError::TimedOut(..)) => "Timed out".to_string(),
+            Err(err) => {
+                tracing::error!("failed to crop video: {:?}", err);
+                "Error".to_string()
+            }
+        };
 
         embed
-            .title(format!(
-                "Download: https://cdn.avrg.dev/{}/{}.mp4",
-                ctx.config.b2_bucket_path_prefix, downloaded_video.downloaded_file_name
-            ))
             .update_field(
-                1,
+                2,
                 EmbedField {
-                    name: "Upload".to_string(),
-                    value: format!("{}ms", upload_time),
+                    name: "Crop".to_string(),
+                    value: crop_value,
                     inline: true,
                 },
             )
@@ -136,3 +355,32 @@ async fn handle_message_create_inner(
 
     Ok(())
 }
+
+fn file_size(path: &str) -> u64 {
+    std::fs::metadata(path)
+        .map(|metadata| metadata.len())
+        .unwrap_or(0)
+}
+
+/// Shows retries on the message, uploads have their own field by then.
+struct EmbedProgress<'a>(&'a mut MieEmbed);
+
+impl Progress for EmbedProgress<'_> {
+    async fn retrying(&mut self, stage: Stage, backoff: &Backoff) {
+        match stage {
+            Stage::Upload => self.0.update_field(
+                1,
+                EmbedField {
+                    name: "Upload".to_string(),
+                    value: backoff.describe(),
+                    inline: true,
+                },
+            ),
+            _ => self.0.title(backoff.describe()),
+        };
+
+        if let Err(err) = self.0.send_or_update().await {
+            tracing::warn!("failed to show retry: {}", err);
+        }
+    }
+}
@@ -0,0 +1,587 @@
+use std::collections::HashMap;
+use std::path::Path as FilePath;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::extract::{Path, Query, State};
+use axum::http::header::{COOKIE, SET_COOKIE};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{Html, IntoResponse, Redirect, Response};
+use axum::routing::get;
+use axum::Router;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::Deserialize;
+use twilight_model::id::marker::{GuildMarker, UserMarker};
+use twilight_model::id::Id;
+
+use crate::links::{page_name, page_query};
+use crate::media::MediaRecord;
+use crate::quota::Usage;
+use crate::server::{base_url, escape};
+use crate::video::MediaKind;
+use crate::AppContext;
+
+const DISCORD_API: &str = "https://discord.com/api/v10";
+const SESSION_COOKIE: &str = "mie_session";
+// Seconds before having to log in again, guild membership is only checked then
+const SESSION_LIFETIME: u64 = 24 * 60 * 60;
+// Seconds someone has to finish logging in on discord
+const LOGIN_LIFETIME: u64 = 10 * 60;
+// Logins anyone can start without being let in, the oldest go first past it
+const MAX_LOGINS: usize = 1024;
+const PAGE_SIZE: usize = 60;
+
+#[derive(Debug, Clone)]
+struct Session {
+    user_id: Id<UserMarker>,
+    // Guilds the user was in when they logged in, with their names
+    guilds: HashMap<Id<GuildMarker>, String>,
+    created_at: u64,
+}
+
+struct GalleryState {
+    ctx: Arc<AppContext>,
+    client: reqwest::Client,
+    sessions: Mutex<HashMap<String, Session>>,
+    // Login state sent to discord, to the page to go back to afterwards
+    logins: Mutex<HashMap<String, (String, u64)>>,
+    usernames: Mutex<HashMap<Id<UserMarker>, String>>,
+}
+
+impl GalleryState {
+    fn session(&self, headers: &HeaderMap) -> Option<Session> {
+        let id = headers
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .find_map(|cookie| cookie.trim().strip_prefix(&format!("{}=", SESSION_COOKIE)))?;
+
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| session.created_at + SESSION_LIFETIME > now());
+        sessions.get(id).cloned()
+    }
+
+    fn redirect_uri(&self) -> String {
+        format!("{}/auth/callback", base_url(&self.ctx.config))
+    }
+
+    /// Falls back to the id when discord doesn't know the user anymore.
+    async fn username(&self, user_id: Id<UserMarker>) -> String {
+        if let Some(name) = self.usernames.lock().unwrap().get(&user_id) {
+            return name.clone();
+        }
+
+        let user = match self.ctx.http.user(user_id).await {
+            Ok(response) => response.model().await.ok(),
+            Err(err) => {
+                tracing::debug!(%user_id, "failed to get user: {}", err);
+                None
+            }
+        };
+        let Some(user) = user else {
+            return user_id.to_string();
+        };
+
+        self.usernames
+            .lock()
+            .unwrap()
+            .insert(user_id, user.name.clone());
+        user.name
+    }
+}
+
+/// Lets guild members browse everything mirrored in their guild, logging in
+/// through discord to prove they're in it.
+pub fn router<S>(ctx: Arc<AppContext>) -> Router<S> {
+    let state = Arc::new(GalleryState {
+        ctx,
+        client: reqwest::Client::new(),
+        sessions: Mutex::default(),
+        logins: Mutex::default(),
+        usernames: Mutex::default(),
+    });
+
+    Router::new()
+        .route("/login", get(login))
+        .route("/auth/callback", get(callback))
+        .route("/gallery", get(guilds))
+        .route("/gallery/{guild_id}", get(guild_gallery))
+        .with_state(state)
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginQuery {
+    next: Option<String>,
+}
+
+async fn login(
+    State(state): State<Arc<GalleryState>>,
+    Query(query): Query<LoginQuery>,
+) -> Response {
+    // Only ever send people back somewhere on this site
+    let next = query
+        .next
+        .filter(|next| next.starts_with('/') && !next.starts_with("//"))
+        .unwrap_or("/gallery".to_string());
+
+    let login_state = random_id();
+    {
+        let mut logins = state.logins.lock().unwrap();
+        logins.retain(|_, (_, created_at)| *created_at + LOGIN_LIFETIME > now());
+        while logins.len() >= MAX_LOGINS {
+            let oldest = logins
+                .iter()
+                .min_by_key(|(_, (_, created_at))| *created_at)
+                .map(|(login_state, _)| login_state.clone());
+            match oldest {
+                Some(oldest) => logins.remove(&oldest),
+                None => break,
+            };
+        }
+        logins.insert(login_state.clone(), (next, now()));
+    }
+
+    Redirect::to(&format!(
+        "https://discord.com/oauth2/authorize?client_id={}&response_type=code&scope=identify%20guilds&redirect_uri={}&state={}",
+        state.ctx.application_id,
+        urlencoding::encode(&state.redirect_uri()),
+        login_state
+    ))
+    .into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct CallbackQuery {
+    code: Option<String>,
+    state: String,
+}
+
+async fn callback(
+    State(state): State<Arc<GalleryState>>,
+    Query(query): Query<CallbackQuery>,
+) -> Response {
+    let login = state.logins.lock().unwrap().remove(&query.state);
+    let (Some((next, _)), Some(code)) = (login, query.code) else {
+        return message(StatusCode::BAD_REQUEST, "That login didn't work, try again");
+    };
+
+    let session = match authorize(&state, &code).await {
+        Ok(session) => session,
+        Err(err) => {
+            tracing::warn!("failed to log in with discord: {:?}", err);
+            return message(StatusCode::BAD_GATEWAY, "Failed to log in with discord");
+        }
+    };
+
+    tracing::info!(user_id = %session.user_id, "logged in to the gallery");
+    let id = random_id();
+    state.sessions.lock().unwrap().insert(id.clone(), session);
+
+    let secure = match base_url(&state.ctx.config).starts_with("https://") {
+        true => "; Secure",
+        false => "",
+    };
+    let cookie = format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}{}",
+        SESSION_COOKIE, id, SESSION_LIFETIME, secure
+    );
+
+    ([(SET_COOKIE, cookie)], Redirect::to(&next)).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct DiscordUser {
+    id: Id<UserMarker>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DiscordGuild {
+    id: Id<GuildMarker>,
+    name: String,
+}
+
+/// Trades the code for a token and looks up who the user is and which guilds
+/// they're in. The token isn't kept, it's only needed once.
+async fn authorize(state: &GalleryState, code: &str) -> anyhow::Result<Session> {
+    let config = &state.ctx.config;
+    let secret = config.discord_client_secret.clone().unwrap_or_default();
+
+    let token: TokenResponse = state
+        .client
+        .post(format!("{}/oauth2/token", DISCORD_API))
+        .basic_auth(state.ctx.application_id, Some(secret))
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &state.redirect_uri()),
+        ])
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let user: DiscordUser = state
+        .client
+        .get(format!("{}/users/@me", DISCORD_API))
+        .bearer_auth(&token.access_token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let guilds: Vec<DiscordGuild> = state
+        .client
+        .get(format!("{}/users/@me/guilds", DISCORD_API))
+        .bearer_auth(&token.access_token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    Ok(Session {
+        user_id: user.id,
+        guilds: guilds
+            .into_iter()
+            .map(|guild| (guild.id, guild.name))
+            .collect(),
+        created_at: now(),
+    })
+}
+
+/// Guilds the user is in that have anything mirrored.
+async fn guilds(State(state): State<Arc<GalleryState>>, headers: HeaderMap) -> Response {
+    let Some(session) = state.session(&headers) else {
+        return Redirect::to("/login?next=/gallery").into_response();
+    };
+
+    let records = match state.ctx.media.all() {
+        Ok(records) => records,
+        Err(err) => {
+            tracing::error!("failed to read media store: {}", err);
+            return message(StatusCode::INTERNAL_SERVER_ERROR, "Failed to read media");
+        }
+    };
+
+    let mut guilds = session
+        .guilds
+        .iter()
+        .map(|(guild_id, name)| {
+            let usage = Usage::of(
+                records
+                    .iter()
+                    .filter(|record| record.guild_id == Some(*guild_id)),
+            );
+            (guild_id, name, usage)
+        })
+        .filter(|(_, _, usage)| usage.files > 0)
+        .collect::<Vec<_>>();
+    guilds.sort_by_key(|(_, name, _)| name.to_lowercase());
+
+    let items = guilds
+        .iter()
+        .map(|(guild_id, name, usage)| {
+            format!(
+                r#"<li><a href="/gallery/{}">{}</a> <span class="meta">{} files, {}</span></li>"#,
+                guild_id,
+                escape(name),
+                usage.files,
+                megabytes(usage.bytes)
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let body = match items.is_empty() {
+        true => "<p>Nothing has been mirrored in any of your servers yet.</p>".to_string(),
+        false => format!("<ul>{}</ul>", items.join("")),
+    };
+    page("Your servers", &body).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct GalleryQuery {
+    q: Option<String>,
+    kind: Option<String>,
+    // Left empty when filtering by anyone
+    uploader: Option<String>,
+    page: Option<usize>,
+}
+
+impl GalleryQuery {
+    fn uploader(&self) -> Option<Id<UserMarker>> {
+        self.uploader.as_deref()?.parse().ok()
+    }
+
+    fn matches(&self, record: &MediaRecord) -> bool {
+        let kind = MediaKind::from_path(FilePath::new(&record.file_name));
+        let kind_matches = match self.kind.as_deref() {
+            Some("video") => kind == MediaKind::Video,
+            Some("image") => kind == MediaKind::Image,
+            _ => true,
+        };
+
+        let search = self.q.as_deref().unwrap_or_default().trim().to_lowercase();
+        let search_matches = search.is_empty()
+            || [Some(&record.source_url), record.title.as_ref()]
+                .into_iter()
+                .flatten()
+                .any(|text| text.to_lowercase().contains(&search));
+
+        kind_matches
+            && search_matches
+            && self
+                .uploader()
+                .is_none_or(|uploader| record.uploader_id == uploader)
+    }
+
+    /// Link to another page with the same filters.
+    fn page_link(&self, guild_id: Id<GuildMarker>, page: usize) -> String {
+        let mut params = vec![format!("page={}", page)];
+        if let Some(q) = self.q.as_deref().filter(|q| !q.is_empty()) {
+            params.push(format!("q={}", urlencoding::encode(q)));
+        }
+        if let Some(kind) = &self.kind {
+            params.push(format!("kind={}", urlencoding::encode(kind)));
+        }
+        if let Some(uploader) = self.uploader() {
+            params.push(format!("uploader={}", uploader));
+        }
+
+        format!("/gallery/{}?{}", guild_id, params.join("&"))
+    }
+}
+
+async fn guild_gallery(
+    State(state): State<Arc<GalleryState>>,
+    Path(guild_id): Path<Id<GuildMarker>>,
+    Query(query): Query<GalleryQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(session) = state.session(&headers) else {
+        let next = urlencoding::encode(&format!("/gallery/{}", guild_id)).into_owned();
+        return Redirect::to(&format!("/login?next={}", next)).into_response();
+    };
+
+    // Same answer as a guild with nothing in it, so it doesn't leak which exist
+    let Some(guild_name) = session.guilds.get(&guild_id) else {
+        return message(StatusCode::NOT_FOUND, "Nothing here");
+    };
+
+    let mut records = match state.ctx.media.all() {
+        Ok(records) => records,
+        Err(err) => {
+            tracing::error!("failed to read media store: {}", err);
+            return message(StatusCode::INTERNAL_SERVER_ERROR, "Failed to read media");
+        }
+    };
+    records.retain(|record| record.guild_id == Some(guild_id));
+    records.sort_by_key(|record| std::cmp::Reverse(record.created_at));
+
+    let usage = Usage::of(&records);
+    let mut uploader_options = vec![r#"<option value="">Anyone</option>"#.to_string()];
+    for (uploader_id, _, files) in &usage.uploaders {
+        let selected = match query.uploader() == Some(*uploader_id) {
+            true => " selected",
+            false => "",
+        };
+        let username = state.username(*uploader_id).await;
+        uploader_options.push(format!(
+            r#"<option value="{}"{}>{} ({})</option>"#,
+            uploader_id,
+            selected,
+            escape(&username),
+            files
+        ));
+    }
+
+    let kind_options = [("", "Everything"), ("video", "Videos"), ("image", "Images")]
+        .iter()
+        .map(|(value, label)| {
+            let selected = match query.kind.as_deref().unwrap_or_default() == *value {
+                true => " selected",
+                false => "",
+            };
+            format!(
+                r#"<option value="{}"{}>{}</option>"#,
+                value, selected, label
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let filtered = records
+        .iter()
+        .filter(|record| query.matches(record))
+        .collect::<Vec<_>>();
+    let page_number = query.page.unwrap_or(1).max(1);
+
+    let mut items = vec![];
+    for record in filtered
+        .iter()
+        .skip((page_number - 1) * PAGE_SIZE)
+        .take(PAGE_SIZE)
+    {
+        let name = page_name(&record.file_name);
+        let title = record.title.as_deref().unwrap_or(name);
+        let query = match page_query(&state.ctx.config, name) {
+            Ok(query) => query,
+            Err(err) => {
+                tracing::error!("failed to sign page: {}", err);
+                return message(StatusCode::INTERNAL_SERVER_ERROR, "Failed to sign links");
+            }
+        };
+        let uploader = state.username(record.uploader_id).await;
+        items.push(format!(
+            r#"<figure><a href="/m/{name}{query}"><img src="/m/{name}/thumbnail.jpg{query}" alt="" loading="lazy"></a><figcaption><a href="/m/{name}{query}">{title}</a><span class="meta">{date} by {uploader}, {size}</span><a class="meta" href="{source}" rel="noreferrer">{source_text}</a></figcaption></figure>"#,
+            name = escape(name),
+            query = escape(&query),
+            title = escape(title),
+            date = date(record.created_at),
+            uploader = escape(&uploader),
+            size = megabytes(record.size),
+            source = escape(&record.source_url),
+            source_text = escape(&record.source_url),
+        ));
+    }
+
+    let mut pages = vec![];
+    if page_number > 1 {
+        pages.push(format!(
+            r#"<a href="{}">Newer</a>"#,
+            escape(&query.page_link(guild_id, page_number - 1))
+        ));
+    }
+    if filtered.len() > page_number * PAGE_SIZE {
+        pages.push(format!(
+            r#"<a href="{}">Older</a>"#,
+            escape(&query.page_link(guild_id, page_number + 1))
+        ));
+    }
+
+    let body = format!(
+        r#"<p class="meta">{files} files, {size} &middot; <a href="/gallery">All servers</a></p>
+<form method="get">
+<input type="search" name="q" value="{q}" placeholder="Search titles and links">
+<select name="kind">{kinds}</select>
+<select name="uploader">{uploaders}</select>
+<button type="submit">Filter</button>
+</form>
+<div class="grid">{items}</div>
+<p class="pages">{pages}</p>"#,
+        files = usage.files,
+        size = megabytes(usage.bytes),
+        q = escape(query.q.as_deref().unwrap_or_default()),
+        kinds = kind_options.join(""),
+        uploaders = uploader_options.join(""),
+        items = match items.is_empty() {
+            true => "<p>Nothing matches.</p>".to_string(),
+            false => items.join("\n"),
+        },
+        pages = pages.join(" "),
+    );
+
+    page(guild_name, &body).into_response()
+}
+
+fn page(title: &str, body: &str) -> Html<String> {
+    Html(format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{title} - mie</title>
+<style>body{{margin:0 auto;max-width:1200px;padding:1em;background:#111;color:#eee;font-family:sans-serif}}a{{color:#b37cfa}}.meta{{display:block;color:#999;font-size:.85em;overflow:hidden;text-overflow:ellipsis;white-space:nowrap}}li .meta{{display:inline}}form{{display:flex;gap:.5em;flex-wrap:wrap;margin:1em 0}}input,select,button{{background:#222;color:#eee;border:1px solid #444;padding:.4em}}.grid{{display:grid;grid-template-columns:repeat(auto-fill,minmax(220px,1fr));gap:1em}}figure{{margin:0;background:#1b1b1b}}figure img{{width:100%;aspect-ratio:16/9;object-fit:cover;background:#000;display:block}}figcaption{{padding:.5em}}figcaption>a:first-child{{display:block;overflow:hidden;text-overflow:ellipsis;white-space:nowrap}}</style>
+</head>
+<body>
+<h1>{title}</h1>
+{body}
+</body>
+</html>
+"#,
+        title = escape(title),
+        body = body,
+    ))
+}
+
+fn message(status: StatusCode, text: &str) -> Response {
+    (
+        status,
+        page(text, "<p><a href=\"/gallery\">Back to the gallery</a></p>"),
+    )
+        .into_response()
+}
+
+fn megabytes(bytes: u64) -> String {
+    format!("{:.1} MB", bytes as f64 / 1_000_000.0)
+}
+
+/// Unix seconds as a UTC date, e.g. `2024-03-01`.
+fn date(secs: u64) -> String {
+    // Days to a civil date, from Howard Hinnant's date algorithms
+    let days = (secs / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+fn random_id() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_dates() {
+        let cases = [
+            (0, "1970-01-01"),
+            (1_704_067_199, "2023-12-31"),
+            (1_735_689_600, "2025-01-01"),
+        ];
+        for (secs, expected) in cases {
+            assert_eq!(date(secs), expected, "{}", secs);
+        }
+    }
+
+    #[test]
+    fn handles_leap_years() {
+        assert_eq!(date(951_782_400), "2000-02-29");
+        assert_eq!(date(1_709_164_800), "2024-02-29");
+        // Not a leap year, divisible by 100 but not 400
+        assert_eq!(date(4_107_456_000), "2100-02-28");
+        assert_eq!(date(4_107_542_400), "2100-03-01");
+    }
+}
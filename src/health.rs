@@ -0,0 +1,169 @@
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use backblaze_b2_client::client::B2ClientStatus;
+use serde::Serialize;
+use tokio::process::Command;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use twilight_gateway::Event;
+
+use crate::env::Config;
+use crate::AppContext;
+
+// Readiness checks spawn yt-dlp and call b2, don't redo them on every probe
+const READINESS_TTL: Duration = Duration::from_secs(30);
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub detail: String,
+}
+
+impl Check {
+    fn new(result: Result<String, String>) -> Self {
+        match result {
+            Ok(detail) => Check { ok: true, detail },
+            Err(detail) => Check { ok: false, detail },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Readiness {
+    pub gateway_connected: bool,
+    pub last_event_secs_ago: u64,
+    pub b2: Check,
+    pub ytdlp: Check,
+}
+
+impl Readiness {
+    pub fn ready(&self) -> bool {
+        self.gateway_connected && self.b2.ok && self.ytdlp.ok
+    }
+
+    /// Drops the raw errors and versions, for callers that aren't trusted.
+    pub fn without_details(mut self) -> Self {
+        self.b2.detail.clear();
+        self.ytdlp.detail.clear();
+        self
+    }
+}
+
+/// What the gateway has been up to, for the health endpoints and the watchdog.
+pub struct Health {
+    connected: AtomicBool,
+    // Unix seconds, heartbeat acks count so a quiet guild still shows up
+    last_event: AtomicU64,
+    // Last b2 and yt-dlp checks and when they ran
+    checked: Mutex<Option<(Instant, (Check, Check))>>,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Health {
+            connected: AtomicBool::new(false),
+            // Gives the shard the same grace period to connect at startup
+            last_event: AtomicU64::new(now()),
+            checked: Mutex::default(),
+        }
+    }
+
+    pub fn event_received(&self, event: &Event) {
+        self.last_event.store(now(), Ordering::Relaxed);
+
+        match event {
+            Event::Ready(_) | Event::Resumed => self.connected.store(true, Ordering::Relaxed),
+            Event::GatewayClose(_)
+            | Event::GatewayReconnect
+            | Event::GatewayInvalidateSession(_) => self.connected.store(false, Ordering::Relaxed),
+            _ => {}
+        }
+    }
+
+    pub fn connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// Seconds since the gateway last sent anything.
+    pub fn silent_for(&self) -> u64 {
+        now().saturating_sub(self.last_event.load(Ordering::Relaxed))
+    }
+
+    /// Whether the process is worth keeping around, only a stalled gateway
+    /// needs a restart.
+    pub fn is_live(&self, config: &Config) -> bool {
+        self.silent_for() <= config.gateway_timeout
+    }
+
+    /// Whether jobs would go through right now.
+    pub async fn readiness(&self, ctx: &AppContext) -> Readiness {
+        let mut checked = self.checked.lock().await;
+        let fresh = checked
+            .as_ref()
+            .filter(|(checked_at, _)| checked_at.elapsed() < READINESS_TTL)
+            .map(|(_, checks)| checks.clone());
+
+        let (b2, ytdlp) = match fresh {
+            Some(checks) => checks,
+            None => {
+                let checks = (
+                    Check::new(check_b2(ctx).await),
+                    Check::new(check_ytdlp().await),
+                );
+                *checked = Some((Instant::now(), checks.clone()));
+                checks
+            }
+        };
+
+        Readiness {
+            gateway_connected: self.connected(),
+            last_event_secs_ago: self.silent_for(),
+            b2,
+            ytdlp,
+        }
+    }
+}
+
+/// Asks for an upload url, which is free and fails once the key stops working.
+async fn check_b2(ctx: &AppContext) -> Result<String, String> {
+    if matches!(ctx.b2.status(), B2ClientStatus::KeyExpired) {
+        return Err("application key expired".to_string());
+    }
+
+    let b2 = ctx.b2.basic_client();
+    let upload_url = b2.get_upload_url(ctx.config.b2_bucket_id.clone());
+    match tokio::time::timeout(CHECK_TIMEOUT, upload_url).await {
+        Ok(Ok(_)) => Ok("authorized".to_string()),
+        Ok(Err(err)) => Err(format!("{:?}", err)),
+        Err(_) => Err("timed out".to_string()),
+    }
+}
+
+async fn check_ytdlp() -> Result<String, String> {
+    let output = Command::new("yt-dlp")
+        .arg("--version")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .output();
+
+    match tokio::time::timeout(CHECK_TIMEOUT, output).await {
+        Ok(Ok(output)) if output.status.success() => {
+            Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        }
+        Ok(Ok(output)) => Err(String::from_utf8_lossy(&output.stderr).trim().to_string()),
+        Ok(Err(err)) => Err(err.to_string()),
+        Err(_) => Err("timed out".to_string()),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,386 @@
+use std::error::Error;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use backblaze_b2_client::definitions::bodies::B2GetDownloadAuthorizationBody;
+use backblaze_b2_client::simple_client::B2SimpleClient;
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use twilight_model::application::interaction::Interaction;
+use twilight_model::channel::message::component::ButtonStyle;
+use twilight_model::channel::message::{Component, Embed, MessageFlags};
+use twilight_model::http::interaction::InteractionResponseType;
+
+use crate::components::{action_row, button, respond};
+use crate::env::Config;
+use crate::video::MediaKind;
+use crate::AppContext;
+
+type DynamicResult<T = ()> = Result<T, Box<dyn Error + Send + Sync>>;
+
+pub const REFRESH_ID: &str = "refresh-links";
+
+/// Link to the file on the cdn. Private buckets get a download authorization
+/// for just this file attached, which stops working after the link lifetime.
+pub async fn file_url(
+    config: &Config,
+    b2: &B2SimpleClient,
+    file_name: &str,
+) -> DynamicResult<String> {
+    let url = format!("{}/{}", config.cdn_url.trim_end_matches('/'), file_name);
+    if !config.private_links {
+        return Ok(url);
+    }
+
+    let authorization = b2
+        .get_download_authorization(
+            B2GetDownloadAuthorizationBody::builder()
+                .bucket_id(config.b2_bucket_id.clone())
+                .file_name_prefix(file_name.to_string())
+                .valid_duration_in_seconds(config.link_lifetime)
+                .build(),
+        )
+        .await?;
+
+    Ok(format!(
+        "{}?Authorization={}",
+        url,
+        urlencoding::encode(&authorization.authorization_token)
+    ))
+}
+
+/// Link handed out to users, through the media server when there is one.
+/// Videos get its landing page, images already embed fine so they go
+/// straight to the file. Private links to the server carry its own
+/// signature, which runs out after the same lifetime as b2's.
+pub async fn public_link(
+    config: &Config,
+    b2: &B2SimpleClient,
+    file_name: &str,
+) -> DynamicResult<String> {
+    let Some(public_url) = &config.public_url else {
+        return file_url(config, b2, file_name).await;
+    };
+
+    let name = page_name(file_name);
+    let page = format!("{}/m/{}", public_url.trim_end_matches('/'), name);
+    let query = page_query(config, name)?;
+    match MediaKind::from_path(Path::new(file_name)) {
+        MediaKind::Video => Ok(format!("{}{}", page, query)),
+        _ => Ok(format!("{}/file{}", page, query)),
+    }
+}
+
+/// Query string that gets a media server page past its signature check,
+/// empty when links aren't private. Covers the file and thumbnail too.
+pub fn page_query(config: &Config, name: &str) -> Result<String, ErrorStack> {
+    if !config.private_links {
+        return Ok(String::new());
+    }
+
+    let expires = now() + config.link_lifetime;
+    Ok(format!(
+        "?expires={}&signature={}",
+        expires,
+        sign_page(config, name, expires)?
+    ))
+}
+
+/// Whether the signature is ours and hasn't run out, anything goes when
+/// links aren't private.
+pub fn verify_page(
+    config: &Config,
+    name: &str,
+    expires: Option<u64>,
+    signature: Option<&str>,
+) -> bool {
+    if !config.private_links {
+        return true;
+    }
+
+    let (Some(expires), Some(signature)) = (expires, signature) else {
+        return false;
+    };
+    if expires < now() {
+        return false;
+    }
+
+    match sign_page(config, name, expires) {
+        Ok(expected) => {
+            expected.len() == signature.len()
+                && openssl::memcmp::eq(expected.as_bytes(), signature.as_bytes())
+        }
+        Err(err) => {
+            tracing::error!("failed to sign page: {}", err);
+            false
+        }
+    }
+}
+
+fn sign_page(config: &Config, name: &str, expires: u64) -> Result<String, ErrorStack> {
+    let key = PKey::hmac(config.link_secret.as_bytes())?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(format!("{}:{}", name, expires).as_bytes())?;
+
+    Ok(signer
+        .sign_to_vec()?
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Name the media server knows the file by, the key without the prefix.
+pub fn page_name(file_name: &str) -> &str {
+    file_name.rsplit('/').next().unwrap_or(file_name)
+}
+
+/// Whether links handed out to users stop working after a while.
+pub fn links_expire(config: &Config) -> bool {
+    config.private_links
+}
+
+/// Button that gets fresh links, only shown when links expire.
+pub fn refresh_components(config: &Config) -> Vec<Component> {
+    match links_expire(config) {
+        true => vec![action_row(vec![button(
+            REFRESH_ID.to_string(),
+            "Refresh links",
+            ButtonStyle::Secondary,
+        )])],
+        false => vec![],
+    }
+}
+
+/// Swaps every link on the message for a freshly signed one. Works on any
+/// message whose uploads are still tracked, even from before a restart.
+pub async fn refresh_links(ctx: Arc<AppContext>, interaction: Interaction) {
+    // Signing every file can take longer than discord waits for an answer
+    respond(
+        &ctx,
+        &interaction,
+        InteractionResponseType::DeferredUpdateMessage,
+        None,
+    )
+    .await;
+
+    if let Err(err) = refresh_links_inner(&ctx, &interaction).await {
+        tracing::error!("failed to refresh links: {:?}", err);
+        tell(&ctx, &interaction, "Failed to refresh links").await;
+    }
+}
+
+async fn refresh_links_inner(ctx: &AppContext, interaction: &Interaction) -> DynamicResult {
+    let message = interaction
+        .message
+        .as_ref()
+        .ok_or("interaction has no message")?;
+
+    let records = ctx.media.for_message(message.id)?;
+
+    if records.is_empty() {
+        tell(ctx, interaction, "These files are gone").await;
+        return Ok(());
+    }
+
+    // Messages can carry links signed at different times than the record's,
+    // so links are matched on everything before the signature
+    let b2 = ctx.b2.basic_client();
+    let mut content = message.content.clone();
+    let mut embeds = message.embeds.clone();
+    for mut record in records {
+        let url = public_link(&ctx.config, &b2, &record.file_name).await?;
+        let unsigned = url.split('?').next().unwrap_or(&url);
+        content = swap_link(&content, unsigned, &url);
+        for embed in &mut embeds {
+            replace_link(embed, unsigned, &url);
+        }
+
+        record.url = url;
+        ctx.media.insert(&record);
+    }
+
+    ctx.http
+        .interaction(ctx.application_id)
+        .update_response(&interaction.token)
+        .content(Some(&content))?
+        .embeds(Some(&embeds))?
+        .await?;
+
+    Ok(())
+}
+
+/// Only shown to whoever pressed the button, the message was already deferred.
+async fn tell(ctx: &AppContext, interaction: &Interaction, content: &str) {
+    let client = ctx.http.interaction(ctx.application_id);
+    let followup = client
+        .create_followup(&interaction.token)
+        .flags(MessageFlags::EPHEMERAL)
+        .content(content);
+    let result = match followup {
+        Ok(followup) => followup.await.map(|_| ()).map_err(|err| err.to_string()),
+        Err(err) => Err(err.to_string()),
+    };
+    if let Err(err) = result {
+        tracing::warn!("failed to answer refresh: {}", err);
+    }
+}
+
+fn replace_link(embed: &mut Embed, unsigned: &str, new: &str) {
+    let texts = [&mut embed.title, &mut embed.description, &mut embed.url];
+    for text in texts.into_iter().flatten() {
+        *text = swap_link(text, unsigned, new);
+    }
+
+    if let Some(image) = &mut embed.image {
+        image.url = swap_link(&image.url, unsigned, new);
+    }
+    for field in &mut embed.fields {
+        field.value = swap_link(&field.value, unsigned, new);
+    }
+}
+
+/// Replaces every link to the unsigned url in the text, whatever signature
+/// it had, and leaves links that only start the same alone.
+fn swap_link(text: &str, unsigned: &str, new: &str) -> String {
+    let mut swapped = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(unsigned) {
+        let after = &rest[start + unsigned.len()..];
+        let end = after
+            .find(|c: char| c.is_whitespace() || c == '>' || c == ')')
+            .unwrap_or(after.len());
+
+        swapped += &rest[..start];
+        match after[..end].is_empty() || after.starts_with('?') {
+            true => swapped += new,
+            false => swapped += &rest[start..start + unsigned.len() + end],
+        }
+        rest = &after[end..];
+    }
+
+    swapped + rest
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::*;
+    use crate::env::test_config;
+
+    fn private_config() -> Config {
+        Config {
+            private_links: true,
+            link_secret: "secret".to_string(),
+            ..test_config()
+        }
+    }
+
+    // Pulls expires and signature back out of a query from page_query
+    fn parse_query(query: &str) -> (Option<u64>, Option<String>) {
+        let url = Url::parse(&format!("https://example.com/m/clip.mp4{}", query)).unwrap();
+        let mut expires = None;
+        let mut signature = None;
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "expires" => expires = value.parse().ok(),
+                "signature" => signature = Some(value.into_owned()),
+                _ => {}
+            }
+        }
+        (expires, signature)
+    }
+
+    #[test]
+    fn signs_with_hmac_sha256() {
+        assert_eq!(
+            sign_page(&private_config(), "clip.mp4", 1_700_000_000).unwrap(),
+            "1723a8ae9c2c48c1c69c0b3a233be654e6717c61fda498702f915c44ba3180f7"
+        );
+    }
+
+    #[test]
+    fn signed_pages_verify() {
+        let config = private_config();
+        let (expires, signature) = parse_query(&page_query(&config, "clip.mp4").unwrap());
+        assert!(expires.is_some_and(|expires| expires > now()));
+        assert!(verify_page(
+            &config,
+            "clip.mp4",
+            expires,
+            signature.as_deref()
+        ));
+    }
+
+    #[test]
+    fn rejects_tampered_signatures() {
+        let config = private_config();
+        let (expires, signature) = parse_query(&page_query(&config, "clip.mp4").unwrap());
+        let signature = signature.unwrap();
+        let expires = expires.unwrap();
+
+        // Another file, a later expiry, a different key
+        assert!(!verify_page(
+            &config,
+            "other.mp4",
+            Some(expires),
+            Some(&signature)
+        ));
+        assert!(!verify_page(
+            &config,
+            "clip.mp4",
+            Some(expires + 1),
+            Some(&signature)
+        ));
+        let other_key = Config {
+            link_secret: "other".to_string(),
+            ..private_config()
+        };
+        assert!(!verify_page(
+            &other_key,
+            "clip.mp4",
+            Some(expires),
+            Some(&signature)
+        ));
+
+        assert!(!verify_page(
+            &config,
+            "clip.mp4",
+            Some(expires),
+            Some(&signature[1..])
+        ));
+        assert!(!verify_page(&config, "clip.mp4", Some(expires), Some("")));
+        assert!(!verify_page(&config, "clip.mp4", Some(expires), None));
+        assert!(!verify_page(&config, "clip.mp4", None, Some(&signature)));
+    }
+
+    #[test]
+    fn rejects_expired_signatures() {
+        let config = private_config();
+        let expires = now() - 1;
+        let signature = sign_page(&config, "clip.mp4", expires).unwrap();
+        assert!(!verify_page(
+            &config,
+            "clip.mp4",
+            Some(expires),
+            Some(&signature)
+        ));
+    }
+
+    #[test]
+    fn public_links_need_no_signature() {
+        let config = test_config();
+        assert_eq!(page_query(&config, "clip.mp4").unwrap(), "");
+        assert!(verify_page(&config, "clip.mp4", None, None));
+    }
+}
@@ -1,13 +1,36 @@
+mod api;
+mod cache;
 mod commands;
+mod components;
+mod convert;
+mod cookies;
+mod crop;
+mod direct;
+mod disk;
 mod embed;
 mod env;
 mod errors;
 mod event_handlers;
+mod gallery;
+mod health;
+mod links;
+mod media;
+mod metrics;
+mod playlist;
+mod probe;
+mod proxy;
+mod quota;
+mod retention;
+mod retry;
+mod server;
+mod stage;
 mod upload;
 mod video;
+mod workspace;
 
 use std::error::Error;
 use std::sync::Arc;
+use std::time::Duration;
 
 use backblaze_b2_client::client::B2Client;
 use serde::Serialize;
@@ -16,18 +39,35 @@ use twilight_gateway::{ConfigBuilder, Event, EventTypeFlags, Intents, Shard, Sha
 use twilight_http::routing::Route;
 use twilight_http::Client as HttpClient;
 use twilight_model::application::command::{CommandOption, CommandType};
+use twilight_model::application::interaction::InteractionData;
 use twilight_model::id::marker::ApplicationMarker;
 use twilight_model::id::Id;
 use vesper::prelude::Framework;
 
+use self::cache::ResultCache;
+use self::commands::cookies::cookies;
 use self::commands::download::download;
+use self::commands::gif::gif;
+use self::commands::status::status;
+use self::commands::usage::usage;
+use self::components::ComponentWaiters;
+use self::disk::DiskScheduler;
 use self::env::{create_config, load_env, Config};
 use self::event_handlers::messsage_create::handle_message_create;
+use self::health::Health;
+use self::links::{refresh_links, REFRESH_ID};
+use self::media::MediaStore;
 
 pub struct AppContext {
     config: Config,
     http: Arc<HttpClient>,
     b2: Arc<B2Client>,
+    application_id: Id<ApplicationMarker>,
+    components: ComponentWaiters,
+    disk: DiskScheduler,
+    cache: ResultCache,
+    media: MediaStore,
+    health: Health,
 }
 
 #[tokio::main]
@@ -40,6 +80,17 @@ async fn main() -> anyhow::Result<()> {
 
     let config = create_config();
 
+    // Nothing is running yet, so anything in the work dir was left by a crash
+    workspace::sweep_stale(&config.work_dir);
+    // Disk usage is read from the work dir before any job gets to create it
+    if let Err(err) = std::fs::create_dir_all(&config.work_dir) {
+        tracing::error!(
+            work_dir = config.work_dir,
+            "failed to create work dir: {}",
+            err
+        );
+    }
+
     let shard_config = ConfigBuilder::new(
         config.discord_token.clone(),
         Intents::GUILD_MESSAGES | Intents::MESSAGE_CONTENT | Intents::DIRECT_MESSAGES,
@@ -48,7 +99,12 @@ async fn main() -> anyhow::Result<()> {
         EventTypeFlags::MESSAGE_CREATE
             | EventTypeFlags::GATEWAY_HELLO
             | EventTypeFlags::READY
-            | EventTypeFlags::INTERACTION_CREATE,
+            | EventTypeFlags::INTERACTION_CREATE
+            // Only for keeping track of the connection
+            | EventTypeFlags::RESUMED
+            | EventTypeFlags::GATEWAY_HEARTBEAT_ACK
+            | EventTypeFlags::GATEWAY_RECONNECT
+            | EventTypeFlags::GATEWAY_INVALIDATE_SESSION,
     )
     .build();
 
@@ -71,11 +127,30 @@ async fn main() -> anyhow::Result<()> {
         config: config.clone(),
         http: http.clone(),
         b2,
+        application_id: app_id,
+        components: ComponentWaiters::default(),
+        disk: DiskScheduler::default(),
+        cache: ResultCache::open(&config),
+        media: MediaStore::open(&config),
+        health: Health::new(),
     });
 
+    tokio::spawn(retention::run_sweeper(app_context.clone()));
+    tokio::spawn(server::serve(app_context.clone()));
+    tokio::spawn(server::serve_internal(app_context.clone()));
+
     let framework = Arc::new(
         Framework::builder(http.clone(), app_id, app_context.clone())
             .command(download)
+            .command(gif)
+            .command(status)
+            .command(cookies)
+            .group(|group| {
+                group
+                    .name("mie")
+                    .description("Things about mie itself")
+                    .command(usage)
+            })
             .build(),
     );
 
@@ -84,27 +159,34 @@ async fn main() -> anyhow::Result<()> {
 
     // Manually create commands so I can use contexts as it currently
     // is not supported in the released versions
-    for cmd in framework.commands.values() {
+    let commands = framework.commands.values().map(|cmd| {
         let options = cmd
             .arguments
             .iter()
             .map(|a| a.as_option(&framework, cmd))
             .collect::<Vec<_>>();
+        (cmd.name, cmd.description, options)
+    });
+    let groups = framework
+        .groups
+        .values()
+        .map(|group| (group.name, group.description, group.get_options(&framework)));
 
+    for (name, description, options) in commands.chain(groups) {
         let c = reqwest::Client::new();
         let path = Route::SetGlobalCommands {
             application_id: app_id.into(),
         }
         .to_string();
 
-        tracing::info!("creating {} command", cmd.name);
+        tracing::info!("creating {} command", name);
         c.post(format!("https://discord.com/api/v10/{}", path))
             .header("Authorization", format!("Bot {}", config.discord_token))
             .json(&GlobalCommandBody {
                 application_id: Some(app_id),
-                description: Some(cmd.description),
+                description: Some(description),
                 kind: CommandType::ChatInput,
-                name: cmd.name,
+                name,
                 options: Some(options),
                 contexts: vec![0, 1, 2],
                 integration_types: vec![0, 1],
@@ -113,18 +195,32 @@ async fn main() -> anyhow::Result<()> {
             .await?;
     }
 
+    // Heartbeats are acked every 40 seconds or so, going quiet for longer
+    // means the connection is stuck. Exiting lets whatever runs us restart it
+    let watchdog = Duration::from_secs(config.gateway_timeout);
     loop {
-        match shard.next_event().await {
-            Ok(item) => {
+        let next = tokio::time::timeout(watchdog, shard.next_event()).await;
+        match next {
+            Ok(Ok(item)) => {
+                app_context.health.event_received(&item);
                 tokio::spawn(handle_event(
                     Arc::clone(&app_context),
                     framework.clone(),
                     item,
                 ));
             }
-            Err(err) => {
+            Ok(Err(err)) => {
                 tracing::warn!(source = ?err, "error receiving event");
             }
+            Err(_) => {}
+        }
+
+        if !app_context.health.is_live(&config) {
+            tracing::error!(
+                "no gateway events for {}s, exiting",
+                app_context.health.silent_for()
+            );
+            anyhow::bail!("gateway went silent");
         }
     }
 }
@@ -159,7 +255,19 @@ async fn handle_event(
 
         Event::InteractionCreate(i) => {
             tracing::info!("hello interation");
-            framework.process(i.0).await;
+            // Buttons on our own embeds go to whoever is waiting on them first
+            if let Some(interaction) = ctx.components.wake(i.0) {
+                match &interaction.data {
+                    Some(InteractionData::MessageComponent(data))
+                        if data.custom_id == REFRESH_ID =>
+                    {
+                        refresh_links(ctx, interaction).await;
+                    }
+                    _ => {
+                        framework.process(interaction).await;
+                    }
+                }
+            }
         }
 
         Event::Ready(_) => {
@@ -168,6 +276,10 @@ async fn handle_event(
         Event::GatewayHello(_) => {
             tracing::info!("discord said hello");
         }
+        Event::GatewayHeartbeatAck => {}
+        Event::Resumed => {
+            tracing::info!("gateway session resumed");
+        }
         _ => {
             tracing::debug!("recieved event, but have no handler {:?}", event);
         }
@@ -0,0 +1,305 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use redb::{Database, ReadableTable, TableDefinition};
+use serde::{Deserialize, Serialize};
+use twilight_model::id::marker::{ChannelMarker, GuildMarker, MessageMarker, UserMarker};
+use twilight_model::id::Id;
+
+use crate::env::Config;
+use crate::upload::UploadedFile;
+use crate::video::DownloadedVideo;
+
+// Bucket key to a json encoded MediaRecord
+const MEDIA: TableDefinition<&str, &[u8]> = TableDefinition::new("media");
+// Message id to the json encoded Attached, for messages that reuse files
+// recorded against another one
+const MESSAGES: TableDefinition<u64, &[u8]> = TableDefinition::new("messages");
+
+/// Files a message links to besides the ones recorded against it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Attached {
+    pub channel_id: Option<Id<ChannelMarker>>,
+    pub file_names: Vec<String>,
+}
+
+/// Where an upload was asked for and where its links were posted.
+#[derive(Debug, Clone, Copy)]
+pub struct MediaOrigin {
+    pub guild_id: Option<Id<GuildMarker>>,
+    // Jobs submitted over the api aren't posted anywhere
+    pub channel_id: Option<Id<ChannelMarker>>,
+    // Only channel messages can be edited later, interaction responses expire
+    pub message_id: Option<Id<MessageMarker>>,
+    pub uploader_id: Id<UserMarker>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MediaRecord {
+    pub file_id: String,
+    pub file_name: String,
+    pub url: String,
+    pub size: u64,
+    pub sha1: Option<String>,
+    pub source_url: String,
+    // Post title if the site had one
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub author: Option<String>,
+    #[serde(default)]
+    pub author_url: Option<String>,
+    // Cached result that links to this file
+    pub cache_key: Option<String>,
+    pub guild_id: Option<Id<GuildMarker>>,
+    pub channel_id: Option<Id<ChannelMarker>>,
+    pub message_id: Option<Id<MessageMarker>>,
+    pub uploader_id: Id<UserMarker>,
+    // Kept around no matter how old it is
+    pub pinned: bool,
+    // Unix seconds
+    pub created_at: u64,
+}
+
+/// Every file we put in the bucket, so they can be cleaned up later.
+/// Failing to open the file only stops new uploads from being tracked.
+pub struct MediaStore {
+    db: Option<Database>,
+}
+
+impl MediaStore {
+    pub fn open(config: &Config) -> Self {
+        let db = match Database::create(&config.media_path) {
+            Ok(db) => Some(db),
+            Err(err) => {
+                tracing::warn!(
+                    config.media_path,
+                    "failed to open media store, uploads won't be tracked: {}",
+                    err
+                );
+                None
+            }
+        };
+
+        MediaStore { db }
+    }
+
+    pub fn record<'a>(
+        &self,
+        origin: &MediaOrigin,
+        video: &DownloadedVideo,
+        cache_key: Option<&str>,
+        files: impl IntoIterator<Item = &'a UploadedFile>,
+    ) {
+        let records = files.into_iter().map(|file| MediaRecord {
+            file_id: file.file_id.clone(),
+            file_name: file.file_name.clone(),
+            url: file.url.clone(),
+            size: file.size,
+            sha1: file.sha1.clone(),
+            source_url: video.og_url.clone(),
+            title: video.title.clone(),
+            author: video.author.clone(),
+            author_url: video.author_url.clone(),
+            cache_key: cache_key.map(str::to_string),
+            guild_id: origin.guild_id,
+            channel_id: origin.channel_id,
+            message_id: origin.message_id,
+            uploader_id: origin.uploader_id,
+            pinned: false,
+            created_at: now(),
+        });
+
+        for record in records {
+            self.insert(&record);
+        }
+    }
+
+    pub fn insert(&self, record: &MediaRecord) {
+        let Some(db) = &self.db else {
+            return;
+        };
+
+        if let Err(err) = write_record(db, record) {
+            tracing::warn!(record.file_name, "failed to write media record: {}", err);
+        }
+    }
+
+    pub fn get(&self, file_name: &str) -> Option<MediaRecord> {
+        let db = self.db.as_ref()?;
+
+        match read_record(db, file_name) {
+            Ok(record) => record,
+            Err(err) => {
+                tracing::warn!(file_name, "failed to read media record: {}", err);
+                None
+            }
+        }
+    }
+
+    pub fn all(&self) -> anyhow::Result<Vec<MediaRecord>> {
+        match &self.db {
+            Some(db) => read_records(db),
+            None => Ok(vec![]),
+        }
+    }
+
+    pub fn remove(&self, file_name: &str) {
+        let Some(db) = &self.db else {
+            return;
+        };
+
+        if let Err(err) = remove_record(db, file_name) {
+            tracing::warn!(file_name, "failed to remove media record: {}", err);
+        }
+    }
+
+    /// Remembers that the message links to the files, so its links can be
+    /// refreshed even though the files were recorded for another message.
+    pub fn attach(
+        &self,
+        channel_id: Id<ChannelMarker>,
+        message_id: Id<MessageMarker>,
+        file_names: &[String],
+    ) {
+        let Some(db) = &self.db else {
+            return;
+        };
+
+        if let Err(err) = write_attached(db, channel_id, message_id, file_names) {
+            tracing::warn!(%message_id, "failed to attach files to message: {}", err);
+        }
+    }
+
+    /// Every file the message links to, still tracked.
+    pub fn for_message(&self, message_id: Id<MessageMarker>) -> anyhow::Result<Vec<MediaRecord>> {
+        let Some(db) = &self.db else {
+            return Ok(vec![]);
+        };
+
+        let attached = read_attached(db, message_id)?;
+        let records = read_records(db)?
+            .into_iter()
+            .filter(|record| {
+                record.message_id == Some(message_id)
+                    || attached.file_names.contains(&record.file_name)
+            })
+            .collect();
+        Ok(records)
+    }
+
+    /// Every message that files were attached to, by message id.
+    pub fn all_attached(&self) -> anyhow::Result<Vec<(Id<MessageMarker>, Attached)>> {
+        let Some(db) = &self.db else {
+            return Ok(vec![]);
+        };
+
+        let read = db.begin_read()?;
+        let table = match read.open_table(MESSAGES) {
+            Ok(table) => table,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut messages = vec![];
+        for entry in table.iter()? {
+            let (message_id, value) = entry?;
+            let Some(message_id) = Id::new_checked(message_id.value()) else {
+                continue;
+            };
+            messages.push((message_id, serde_json::from_slice(value.value())?));
+        }
+        Ok(messages)
+    }
+}
+
+fn read_record(db: &Database, file_name: &str) -> anyhow::Result<Option<MediaRecord>> {
+    let read = db.begin_read()?;
+    let table = match read.open_table(MEDIA) {
+        Ok(table) => table,
+        Err(redb::TableError::TableDoesNotExist(_)) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    match table.get(file_name)? {
+        Some(value) => Ok(Some(serde_json::from_slice(value.value())?)),
+        None => Ok(None),
+    }
+}
+
+fn read_records(db: &Database) -> anyhow::Result<Vec<MediaRecord>> {
+    let read = db.begin_read()?;
+    let table = match read.open_table(MEDIA) {
+        Ok(table) => table,
+        // Nothing has been uploaded yet
+        Err(redb::TableError::TableDoesNotExist(_)) => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut records = vec![];
+    for entry in table.iter()? {
+        let (_, value) = entry?;
+        records.push(serde_json::from_slice(value.value())?);
+    }
+    Ok(records)
+}
+
+fn write_record(db: &Database, record: &MediaRecord) -> anyhow::Result<()> {
+    let value = serde_json::to_vec(record)?;
+    let write = db.begin_write()?;
+    write
+        .open_table(MEDIA)?
+        .insert(record.file_name.as_str(), value.as_slice())?;
+    write.commit()?;
+    Ok(())
+}
+
+fn remove_record(db: &Database, file_name: &str) -> anyhow::Result<()> {
+    let write = db.begin_write()?;
+    write.open_table(MEDIA)?.remove(file_name)?;
+    write.commit()?;
+    Ok(())
+}
+
+fn read_attached(db: &Database, message_id: Id<MessageMarker>) -> anyhow::Result<Attached> {
+    let read = db.begin_read()?;
+    let table = match read.open_table(MESSAGES) {
+        Ok(table) => table,
+        Err(redb::TableError::TableDoesNotExist(_)) => return Ok(Attached::default()),
+        Err(err) => return Err(err.into()),
+    };
+
+    match table.get(message_id.get())? {
+        Some(value) => Ok(serde_json::from_slice(value.value())?),
+        None => Ok(Attached::default()),
+    }
+}
+
+fn write_attached(
+    db: &Database,
+    channel_id: Id<ChannelMarker>,
+    message_id: Id<MessageMarker>,
+    file_names: &[String],
+) -> anyhow::Result<()> {
+    let mut attached = read_attached(db, message_id)?;
+    attached.channel_id = Some(channel_id);
+    for file_name in file_names {
+        if !attached.file_names.contains(file_name) {
+            attached.file_names.push(file_name.clone());
+        }
+    }
+
+    let value = serde_json::to_vec(&attached)?;
+    let write = db.begin_write()?;
+    write
+        .open_table(MESSAGES)?
+        .insert(message_id.get(), value.as_slice())?;
+    write.commit()?;
+    Ok(())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
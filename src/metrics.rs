@@ -0,0 +1,210 @@
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+use url::Url;
+
+use crate::errors::MieError;
+use crate::stage::Stage;
+use crate::AppContext;
+
+// Stages run anywhere from a second to the 15 minute default timeout
+const STAGE_BUCKETS: &[f64] = &[
+    0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 900.0,
+];
+
+static JOBS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "mie_jobs_total",
+        "Links handled, by how they ended and the site they were from",
+        &["outcome", "extractor"]
+    )
+    .unwrap()
+});
+
+static STAGE_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "mie_stage_duration_seconds",
+        "Time spent in each stage of a job",
+        &["stage", "outcome"],
+        STAGE_BUCKETS.to_vec()
+    )
+    .unwrap()
+});
+
+static UPLOADED_BYTES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("mie_uploaded_bytes_total", "Bytes put in the bucket").unwrap()
+});
+
+static YTDLP_PROCESSES: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!("mie_ytdlp_processes", "yt-dlp processes running right now").unwrap()
+});
+
+static DISCORD_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "mie_discord_errors_total",
+        "Failed requests to the discord api, by what they were for",
+        &["request"]
+    )
+    .unwrap()
+});
+
+// Read from the app when scraped rather than kept up to date
+static QUEUE_DEPTH: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "mie_queue_depth",
+        "Jobs waiting for disk space before they can download"
+    )
+    .unwrap()
+});
+
+static DISK_RESERVED: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "mie_disk_reserved_bytes",
+        "Disk space held by running downloads"
+    )
+    .unwrap()
+});
+
+#[derive(Debug, Clone, Copy)]
+pub enum Outcome {
+    Success,
+    // Reused the links from an earlier job
+    Cached,
+    // Turned away before downloading, too big, over quota etc.
+    Refused,
+    Failed,
+}
+
+impl Outcome {
+    fn label(&self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::Cached => "cached",
+            Outcome::Refused => "refused",
+            Outcome::Failed => "failed",
+        }
+    }
+}
+
+impl From<&MieError> for Outcome {
+    fn from(err: &MieError) -> Self {
+        match err {
+            MieError::FileTooLarge(_)
+            | MieError::TooLong(_)
+            | MieError::LiveStream
+            | MieError::InsufficientDiskSpace(_)
+            | MieError::GuildQuotaExceeded(_)
+            | MieError::UserQuotaExceeded(_) => Outcome::Refused,
+            _ => Outcome::Failed,
+        }
+    }
+}
+
+/// Counts a finished job against the site it was for.
+pub fn job_finished(url: &str, outcome: Outcome) {
+    JOBS.with_label_values(&[outcome.label(), extractor(url)])
+        .inc();
+}
+
+pub fn stage_finished(stage: Stage, succeeded: bool, duration: Duration) {
+    let outcome = match succeeded {
+        true => "ok",
+        false => "error",
+    };
+    STAGE_DURATION
+        .with_label_values(&[&stage.to_string(), outcome])
+        .observe(duration.as_secs_f64());
+}
+
+pub fn uploaded(bytes: u64) {
+    UPLOADED_BYTES.inc_by(bytes);
+}
+
+pub fn discord_error(request: &str) {
+    DISCORD_ERRORS.with_label_values(&[request]).inc();
+}
+
+/// Counts a yt-dlp process for as long as it's held.
+pub struct YtDlpProcess;
+
+impl YtDlpProcess {
+    pub fn start() -> Self {
+        YTDLP_PROCESSES.inc();
+        YtDlpProcess
+    }
+}
+
+impl Drop for YtDlpProcess {
+    fn drop(&mut self) {
+        YTDLP_PROCESSES.dec();
+    }
+}
+
+/// Everything in the prometheus text format.
+pub fn render(ctx: &AppContext) -> String {
+    QUEUE_DEPTH.set(ctx.disk.queued() as i64);
+    DISK_RESERVED.set(ctx.disk.reserved() as i64);
+
+    // Make sure every metric shows up, even before anything has happened
+    LazyLock::force(&JOBS);
+    LazyLock::force(&STAGE_DURATION);
+    LazyLock::force(&UPLOADED_BYTES);
+    LazyLock::force(&YTDLP_PROCESSES);
+    LazyLock::force(&DISCORD_ERRORS);
+
+    TextEncoder::new()
+        .encode_to_string(&prometheus::gather())
+        .unwrap_or_else(|err| {
+            tracing::error!("failed to encode metrics: {}", err);
+            String::new()
+        })
+}
+
+// Sites jobs get counted under, keyed by domain, anything else is "other" so a
+// link to every random host doesn't make a new series
+const KNOWN_SITES: &[(&str, &str)] = &[
+    ("youtube.com", "youtube"),
+    ("youtu.be", "youtube"),
+    ("twitter.com", "twitter"),
+    ("x.com", "twitter"),
+    ("fxtwitter.com", "twitter"),
+    ("vxtwitter.com", "twitter"),
+    ("instagram.com", "instagram"),
+    ("tiktok.com", "tiktok"),
+    ("reddit.com", "reddit"),
+    ("redd.it", "reddit"),
+    ("twitch.tv", "twitch"),
+    ("vimeo.com", "vimeo"),
+    ("streamable.com", "streamable"),
+    ("bsky.app", "bluesky"),
+    ("tumblr.com", "tumblr"),
+    ("facebook.com", "facebook"),
+    ("fb.watch", "facebook"),
+    ("soundcloud.com", "soundcloud"),
+    ("imgur.com", "imgur"),
+    ("pixiv.net", "pixiv"),
+];
+
+// The site stands in for yt-dlp's extractor, which failed jobs don't have
+fn extractor(url: &str) -> &'static str {
+    let Some(host) = Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_lowercase))
+    else {
+        return "unknown";
+    };
+
+    KNOWN_SITES
+        .iter()
+        .find(|(domain, _)| {
+            host == *domain
+                || host
+                    .strip_suffix(domain)
+                    .is_some_and(|subdomain| subdomain.ends_with('.'))
+        })
+        .map_or("other", |(_, site)| site)
+}
@@ -0,0 +1,244 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use twilight_model::application::interaction::InteractionData;
+use twilight_model::channel::message::component::ButtonStyle;
+use twilight_model::channel::message::MessageFlags;
+use twilight_model::http::interaction::{InteractionResponseData, InteractionResponseType};
+use twilight_model::id::marker::{GuildMarker, UserMarker};
+use twilight_model::id::Id;
+use url::Url;
+
+use crate::components::{action_row, button, respond};
+use crate::embed::MieEmbed;
+use crate::errors::MieError;
+use crate::links::refresh_components;
+use crate::media::MediaOrigin;
+use crate::metrics::{job_finished, Outcome};
+use crate::stage::{mirror_link, Quiet};
+use crate::AppContext;
+
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(60);
+// Discord refuses descriptions over 4096 characters
+const MAX_DESCRIPTION: usize = 4000;
+// Long error messages and galleries with many links
+const MAX_STATUS: usize = 200;
+
+#[derive(Debug)]
+pub struct Playlist {
+    pub title: String,
+    pub entries: Vec<String>,
+}
+
+/// Asks the author to confirm before mirroring every entry of the playlist,
+/// each entry gets downloaded and uploaded on its own and reported back on
+/// the same embed.
+pub async fn mirror_playlist(
+    ctx: Arc<AppContext>,
+    embed: &mut MieEmbed,
+    mut playlist: Playlist,
+    author_id: Id<UserMarker>,
+    guild_id: Option<Id<GuildMarker>>,
+) -> anyhow::Result<()> {
+    let max_items = ctx.config.playlist_max_items;
+    let total = playlist.entries.len();
+    playlist.entries.truncate(max_items);
+
+    let job_id: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(7)
+        .map(char::from)
+        .collect();
+    let prefix = format!("playlist:{}:", job_id);
+
+    let mut description = format!("Mirror {} videos?", playlist.entries.len());
+    if total > max_items {
+        description += &format!(
+            "\nPlaylist has {} items, only the first {} will be mirrored",
+            total, max_items
+        );
+    }
+
+    let mut receiver = ctx.components.wait(prefix.clone());
+
+    embed
+        .title(format!("Playlist: {}", playlist.title))
+        .description(description)
+        .components(vec![action_row(vec![
+            button(
+                format!("{}confirm", prefix),
+                &format!("Download {}", playlist.entries.len()),
+                ButtonStyle::Success,
+            ),
+            button(
+                format!("{}cancel", prefix),
+                "Cancel",
+                ButtonStyle::Secondary,
+            ),
+        ])])
+        .send_or_update()
+        .await?;
+
+    let confirmed = loop {
+        let interaction = match tokio::time::timeout(CONFIRM_TIMEOUT, receiver.recv()).await {
+            Ok(Some(interaction)) => interaction,
+            _ => break None,
+        };
+
+        if interaction.author_id() != Some(author_id) {
+            respond(
+                &ctx,
+                &interaction,
+                InteractionResponseType::ChannelMessageWithSource,
+                Some(InteractionResponseData {
+                    content: Some("only the person who sent the link can do that".to_string()),
+                    flags: Some(MessageFlags::EPHEMERAL),
+                    ..Default::default()
+                }),
+            )
+            .await;
+            continue;
+        }
+
+        respond(
+            &ctx,
+            &interaction,
+            InteractionResponseType::DeferredUpdateMessage,
+            None,
+        )
+        .await;
+
+        let Some(InteractionData::MessageComponent(data)) = &interaction.data else {
+            continue;
+        };
+        break Some(data.custom_id.ends_with("confirm"));
+    };
+    drop(receiver);
+
+    match confirmed {
+        Some(true) => {}
+        Some(false) => {
+            embed
+                .description("Cancelled".to_string())
+                .components(vec![])
+                .send_or_update()
+                .await?;
+            return Ok(());
+        }
+        None => {
+            embed
+                .description("Timed out waiting for confirmation".to_string())
+                .components(vec![])
+                .send_or_update()
+                .await?;
+            return Ok(());
+        }
+    }
+
+    let origin = MediaOrigin {
+        guild_id,
+        channel_id: Some(embed.channel_id()),
+        message_id: embed.message_id(),
+        uploader_id: author_id,
+    };
+    let mut statuses = vec!["Pending".to_string(); playlist.entries.len()];
+    let mut mirrored = 0;
+    embed.components(vec![]);
+
+    for (index, entry) in playlist.entries.iter().enumerate() {
+        statuses[index] = "Downloading".to_string();
+        update_progress(embed, &playlist, &statuses).await?;
+
+        // Retried quietly, the progress line only shows how it ended
+        let result = match Url::parse(entry) {
+            Ok(url) => mirror_link(&ctx, &url, &origin, &mut Quiet).await,
+            Err(err) => Err(err.into()),
+        };
+
+        statuses[index] = match result {
+            Ok(result) => {
+                mirrored += 1;
+                // Cached entries were recorded against an earlier message
+                if let Some(message_id) = embed.message_id() {
+                    ctx.media
+                        .attach(embed.channel_id(), message_id, &result.all_files());
+                }
+                result.links.join(" ")
+            }
+            Err(err) => {
+                tracing::error!(entry, "failed to mirror playlist entry: {:?}", err);
+                let outcome = err
+                    .downcast_ref::<MieError>()
+                    .map(Outcome::from)
+                    .unwrap_or(Outcome::Failed);
+                job_finished(entry, outcome);
+                match outcome {
+                    Outcome::Refused => format!("Refused: {}", err),
+                    _ => format!("Failed: {}", err),
+                }
+            }
+        };
+    }
+
+    embed
+        .title(format!(
+            "Playlist: {} ({}/{} mirrored)",
+            playlist.title,
+            mirrored,
+            playlist.entries.len()
+        ))
+        .components(refresh_components(&ctx.config));
+    update_progress(embed, &playlist, &statuses).await?;
+
+    Ok(())
+}
+
+/// One line per entry, the ones that don't fit are summed up at the end.
+async fn update_progress(
+    embed: &mut MieEmbed,
+    playlist: &Playlist,
+    statuses: &[String],
+) -> anyhow::Result<()> {
+    let lines = playlist
+        .entries
+        .iter()
+        .zip(statuses)
+        .enumerate()
+        .map(|(index, (entry, status))| {
+            format!(
+                "{}. <{}> {}",
+                index + 1,
+                entry,
+                truncate(status, MAX_STATUS)
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let mut description = String::new();
+    for (index, line) in lines.iter().enumerate() {
+        let more = format!("\n...and {} more", lines.len() - index);
+        if description.chars().count() + line.chars().count() + more.chars().count() + 1
+            > MAX_DESCRIPTION
+        {
+            description += &more;
+            break;
+        }
+
+        if index > 0 {
+            description.push('\n');
+        }
+        description += line;
+    }
+
+    embed.description(description).send_or_update().await?;
+    Ok(())
+}
+
+fn truncate(text: &str, max: usize) -> String {
+    match text.char_indices().nth(max) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text.to_string(),
+    }
+}
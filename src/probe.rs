@@ -0,0 +1,211 @@
+use std::path::{Path, PathBuf};
+use std::process::Output;
+
+use serde_json::Value;
+use tokio::process::Command;
+use url::Url;
+
+use crate::cookies::{auth_args, cookie_file};
+use crate::direct::is_direct_link;
+use crate::env::Config;
+use crate::errors::MieError;
+use crate::metrics::YtDlpProcess;
+use crate::playlist::Playlist;
+use crate::proxy::{is_blocked, network_args, proxy_chain};
+use crate::video::Quality;
+use crate::workspace::Workspace;
+
+#[derive(Debug)]
+pub struct MediaInfo {
+    pub duration: Option<f64>,
+    pub is_live: bool,
+    pub filesize: Option<u64>,
+}
+
+#[derive(Debug)]
+pub enum Probe {
+    Playlist(Playlist),
+    Media(MediaInfo),
+}
+
+#[derive(Debug)]
+pub enum DownloadPlan {
+    Playlist(Playlist),
+    // Quality to download at and the estimated size, if known
+    Download(Quality, Option<u64>),
+}
+
+/// Asks yt-dlp about the url without downloading anything.
+/// Multi video posts (tweets etc.) come back as resolved videos rather than
+/// url entries, so those are treated as a single item.
+pub async fn probe_url(config: &Config, url: &str, quality: Quality) -> Result<Probe, MieError> {
+    // yt-dlp writes the cookie jar back when it exits, give it a copy so a
+    // probe doesn't clobber cookies uploaded while it was running
+    let workspace;
+    let cookies = match cookie_file(config, url) {
+        Some(original) => {
+            workspace = Workspace::create(&config.work_dir).await?;
+            let copy = PathBuf::from(workspace.path("cookies.txt"));
+            tokio::fs::copy(&original, &copy).await.ok().map(|_| copy)
+        }
+        None => None,
+    };
+
+    let mut output = None;
+    for proxy in proxy_chain(config, url) {
+        let result = run_probe(config, url, quality, cookies.as_deref(), proxy.as_deref()).await?;
+        let blocked =
+            !result.status.success() && is_blocked(&String::from_utf8_lossy(&result.stderr));
+        output = Some(result);
+
+        if !blocked {
+            break;
+        }
+        tracing::warn!(url, ?proxy, "probe was blocked, trying the next proxy");
+    }
+
+    // The chain always has at least the direct connection in it
+    let output = output.expect("empty proxy chain");
+
+    if !output.status.success() {
+        return Err(MieError::ProbeFailed(
+            String::from_utf8_lossy(&output.stderr).to_string(),
+        ));
+    }
+
+    let info: Value = serde_json::from_slice(&output.stdout)
+        .map_err(|err| MieError::ProbeFailed(err.to_string()))?;
+
+    let entries = info["entries"].as_array().cloned().unwrap_or_default();
+    let is_url_list = entries
+        .iter()
+        .any(|entry| entry["_type"] == "url" || entry["_type"] == "url_transparent");
+
+    if info["_type"] == "playlist" && is_url_list {
+        let entries = entries
+            .iter()
+            .filter_map(|entry| {
+                entry["url"]
+                    .as_str()
+                    .or(entry["webpage_url"].as_str())
+                    .map(str::to_string)
+            })
+            .collect();
+
+        return Ok(Probe::Playlist(Playlist {
+            title: info["title"].as_str().unwrap_or(url).to_string(),
+            entries,
+        }));
+    }
+
+    // Resolved multi video posts are summed up as if they were one video
+    let items = match info["_type"] == "playlist" {
+        true => entries,
+        false => vec![info],
+    };
+
+    let mut media = MediaInfo {
+        duration: None,
+        is_live: false,
+        filesize: None,
+    };
+
+    for item in items {
+        if let Some(duration) = item["duration"].as_f64() {
+            media.duration = Some(media.duration.unwrap_or(0.0) + duration);
+        }
+
+        media.is_live |= item["is_live"].as_bool().unwrap_or(false);
+
+        if let Some(size) = estimate_filesize(&item) {
+            media.filesize = Some(media.filesize.unwrap_or(0) + size);
+        }
+    }
+
+    Ok(Probe::Media(media))
+}
+
+async fn run_probe(
+    config: &Config,
+    url: &str,
+    quality: Quality,
+    cookies: Option<&Path>,
+    proxy: Option<&str>,
+) -> Result<Output, MieError> {
+    let mut command = Command::new("yt-dlp");
+    command
+        .args(["--flat-playlist", "-J", "--no-warnings"])
+        .args(["-f", quality.format()]);
+
+    let auth = auth_args(config, cookies);
+    for (flag, value) in auth.into_iter().chain(network_args(config, proxy)) {
+        command.arg(flag).args(value);
+    }
+
+    let _process = YtDlpProcess::start();
+    command
+        .arg(url)
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|err| MieError::ProbeFailed(err.to_string()))
+}
+
+/// Works out what to do with the url before downloading anything, refusing
+/// anything over the configured limits. When only the size is the problem
+/// a lower quality is tried before giving up.
+pub async fn plan_download(config: &Config, url: &Url) -> Result<DownloadPlan, MieError> {
+    // Direct links check their size with a HEAD request instead
+    if is_direct_link(url) {
+        return Ok(DownloadPlan::Download(Quality::Best, None));
+    }
+
+    let info = match probe_url(config, url.as_str(), Quality::Best).await {
+        Ok(Probe::Playlist(playlist)) => return Ok(DownloadPlan::Playlist(playlist)),
+        Ok(Probe::Media(info)) => info,
+        // Let the download itself report the error, image posts also end up here
+        Err(err) => {
+            tracing::debug!(url = url.as_str(), "probe failed: {}", err);
+            return Ok(DownloadPlan::Download(Quality::Best, None));
+        }
+    };
+
+    if info.is_live && !config.allow_live_streams {
+        return Err(MieError::LiveStream);
+    }
+
+    if let Some(duration) = info.duration {
+        if duration > config.max_duration as f64 {
+            return Err(MieError::TooLong(duration));
+        }
+    }
+
+    let Some(size) = info
+        .filesize
+        .filter(|size| *size > config.max_download_size)
+    else {
+        return Ok(DownloadPlan::Download(Quality::Best, info.filesize));
+    };
+
+    tracing::info!(url = url.as_str(), size, "too large at best quality");
+    match probe_url(config, url.as_str(), Quality::Low).await {
+        Ok(Probe::Media(low)) if low.filesize.unwrap_or(0) <= config.max_download_size => {
+            Ok(DownloadPlan::Download(Quality::Low, low.filesize))
+        }
+        _ => Err(MieError::FileTooLarge(size)),
+    }
+}
+
+fn estimate_filesize(item: &Value) -> Option<u64> {
+    let size_of = |format: &Value| {
+        format["filesize"]
+            .as_u64()
+            .or(format["filesize_approx"].as_u64())
+    };
+
+    // Separate video and audio streams list their sizes individually
+    match item["requested_formats"].as_array() {
+        Some(formats) => formats.iter().map(size_of).sum(),
+        None => size_of(item),
+    }
+}
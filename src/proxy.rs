@@ -0,0 +1,80 @@
+use reqwest::{Client, Proxy};
+use url::Url;
+
+use crate::env::Config;
+use crate::errors::MieError;
+
+// Bits of yt-dlp and gallery-dl errors that mean the site doesn't like our
+// ip rather than the url being broken
+const BLOCKED_ERRORS: &[&str] = &[
+    "HTTP Error 429",
+    "Too Many Requests",
+    "rate-limit",
+    "rate limit",
+    "HTTP Error 403",
+    "not available in your country",
+    "geo restriction",
+    "geo-restricted",
+    "Sign in to confirm you",
+];
+
+/// Proxies to try for the url in order, `None` means connecting directly.
+/// The first one is the domain's own proxy or the global one, the fallback
+/// list is only used when that gets blocked.
+pub fn proxy_chain(config: &Config, url: &str) -> Vec<Option<String>> {
+    let primary = domain_proxy(config, url).or(config.proxy.clone());
+
+    let mut chain = vec![primary.clone()];
+    chain.extend(
+        config
+            .fallback_proxies
+            .iter()
+            .filter(|proxy| primary.as_ref() != Some(*proxy))
+            .map(|proxy| Some(proxy.clone())),
+    );
+    chain
+}
+
+fn domain_proxy(config: &Config, url: &str) -> Option<String> {
+    let host = Url::parse(url).ok()?.host_str()?.to_lowercase();
+
+    // Subdomains use their parent domain's proxy
+    config
+        .domain_proxies
+        .iter()
+        .find(|(domain, _)| host == *domain || host.ends_with(&format!(".{}", domain)))
+        .map(|(_, proxy)| proxy.clone())
+}
+
+pub fn is_blocked(error: &str) -> bool {
+    BLOCKED_ERRORS.iter().any(|blocked| error.contains(blocked))
+}
+
+/// Extra yt-dlp and gallery-dl arguments for going out through the proxy
+/// and configured source address.
+pub fn network_args(config: &Config, proxy: Option<&str>) -> Vec<(String, Option<String>)> {
+    let mut args = vec![];
+
+    if let Some(proxy) = proxy {
+        args.push(("--proxy".to_string(), Some(proxy.to_string())));
+    }
+
+    if let Some(address) = config.source_address {
+        args.push(("--source-address".to_string(), Some(address.to_string())));
+    }
+
+    args
+}
+
+pub fn http_client(config: &Config, proxy: Option<&str>) -> Result<Client, MieError> {
+    let mut builder = Client::builder().local_address(config.source_address);
+
+    if let Some(proxy) = proxy {
+        let proxy = Proxy::all(proxy).map_err(|err| MieError::ProxyError(err.to_string()))?;
+        builder = builder.proxy(proxy);
+    }
+
+    builder
+        .build()
+        .map_err(|err| MieError::ProxyError(err.to_string()))
+}
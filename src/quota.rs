@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+
+use twilight_model::id::marker::{GuildMarker, UserMarker};
+use twilight_model::id::Id;
+
+use crate::errors::MieError;
+use crate::media::MediaRecord;
+use crate::AppContext;
+
+#[derive(Debug, Default)]
+pub struct Usage {
+    pub bytes: u64,
+    pub files: usize,
+    // Bytes and file count per uploader, biggest first
+    pub uploaders: Vec<(Id<UserMarker>, u64, usize)>,
+}
+
+impl Usage {
+    pub fn of<'a>(records: impl IntoIterator<Item = &'a MediaRecord>) -> Self {
+        let mut usage = Usage::default();
+        let mut uploaders: HashMap<_, (u64, usize)> = HashMap::new();
+
+        for record in records {
+            usage.bytes += record.size;
+            usage.files += 1;
+
+            let uploader = uploaders.entry(record.uploader_id).or_default();
+            uploader.0 += record.size;
+            uploader.1 += 1;
+        }
+
+        usage.uploaders = uploaders
+            .into_iter()
+            .map(|(id, (bytes, files))| (id, bytes, files))
+            .collect();
+        usage
+            .uploaders
+            .sort_by_key(|(_, bytes, _)| std::cmp::Reverse(*bytes));
+        usage
+    }
+}
+
+/// Refuses the upload when it would take the guild or the uploader over
+/// their quota. Uploads go ahead when the media store can't be read.
+pub fn check_quota(
+    ctx: &AppContext,
+    guild_id: Option<Id<GuildMarker>>,
+    uploader_id: Id<UserMarker>,
+    bytes: u64,
+) -> Result<(), MieError> {
+    let config = &ctx.config;
+    if config.guild_quota.is_none() && config.user_quota.is_none() {
+        return Ok(());
+    }
+
+    let records = match ctx.media.all() {
+        Ok(records) => records,
+        Err(err) => {
+            tracing::warn!("failed to read media store, skipping quota check: {}", err);
+            return Ok(());
+        }
+    };
+
+    if let (Some(quota), Some(guild_id)) = (config.guild_quota, guild_id) {
+        let used = Usage::of(
+            records
+                .iter()
+                .filter(|record| record.guild_id == Some(guild_id)),
+        );
+        if used.bytes + bytes > quota {
+            return Err(MieError::GuildQuotaExceeded(quota));
+        }
+    }
+
+    if let Some(quota) = config.user_quota {
+        let used = Usage::of(
+            records
+                .iter()
+                .filter(|record| record.uploader_id == uploader_id),
+        );
+        if used.bytes + bytes > quota {
+            return Err(MieError::UserQuotaExceeded(quota));
+        }
+    }
+
+    Ok(())
+}
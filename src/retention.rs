@@ -0,0 +1,387 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use backblaze_b2_client::definitions::bodies::B2DeleteFileVersionBody;
+use twilight_http::error::ErrorType;
+use twilight_model::id::marker::{ChannelMarker, MessageMarker};
+use twilight_model::id::Id;
+
+use crate::embed::MieEmbed;
+use crate::env::Config;
+use crate::media::MediaRecord;
+use crate::AppContext;
+
+#[derive(Debug, Clone, Copy)]
+enum Expiry {
+    Age(u64),
+    GuildFull,
+}
+
+impl Expiry {
+    fn describe(&self, count: usize) -> String {
+        match self {
+            Expiry::Age(days) => format!("{} files were deleted after {} days", count, days),
+            Expiry::GuildFull => format!(
+                "{} files were deleted to make room for newer uploads",
+                count
+            ),
+        }
+    }
+}
+
+/// Deletes uploads that fall outside the retention policy every so often,
+/// does nothing when no policy is set.
+pub async fn run_sweeper(ctx: Arc<AppContext>) {
+    let config = &ctx.config;
+    if config.retention_days.is_none() && config.retention_guild_bytes.is_none() {
+        tracing::info!("no retention policy set, uploads are kept forever");
+        return;
+    }
+
+    let mut interval = tokio::time::interval(Duration::from_secs(config.retention_interval));
+    loop {
+        interval.tick().await;
+
+        if let Err(err) = sweep(&ctx).await {
+            tracing::error!("retention sweep failed: {:?}", err);
+        }
+    }
+}
+
+async fn sweep(ctx: &Arc<AppContext>) -> anyhow::Result<()> {
+    let mut records = ctx.media.all()?;
+    let posted = posted_in(ctx, &records)?;
+    // Answers for this sweep only, pins come and go between them
+    let mut pins = HashMap::new();
+
+    // Unpinned since last time, these count and expire again
+    for record in records.iter_mut().filter(|record| record.pinned) {
+        let messages = posted.get(&record.file_name).map_or(&[][..], Vec::as_slice);
+        if let Ok(false) = any_pinned(ctx, messages, &mut pins).await {
+            record.pinned = false;
+            ctx.media.insert(record);
+        }
+    }
+
+    let expired = expired_records(&ctx.config, records);
+    if expired.is_empty() {
+        return Ok(());
+    }
+    tracing::info!(count = expired.len(), "deleting expired uploads");
+
+    // Files posted together are handled together, pinning keeps all of them
+    let mut messages: HashMap<_, Vec<_>> = HashMap::new();
+    for (record, expiry) in expired {
+        messages
+            .entry((record.channel_id, record.message_id))
+            .or_default()
+            .push((record, expiry));
+    }
+
+    for ((channel_id, message_id), records) in messages {
+        // Any message the files were posted in keeps them, not just the first
+        let mut linked = vec![];
+        for (record, _) in &records {
+            for message in posted.get(&record.file_name).into_iter().flatten() {
+                if !linked.contains(message) {
+                    linked.push(*message);
+                }
+            }
+        }
+
+        match any_pinned(ctx, &linked, &mut pins).await {
+            Ok(false) => {}
+            Ok(true) => {
+                for (mut record, _) in records {
+                    record.pinned = true;
+                    ctx.media.insert(&record);
+                }
+                continue;
+            }
+            // Try again next sweep rather than deleting something pinned
+            Err(err) => {
+                tracing::warn!(?message_id, "failed to check if message is pinned: {}", err);
+                continue;
+            }
+        }
+
+        let expiry = records[0].1;
+        let mut deleted = 0;
+        for (record, _) in &records {
+            match delete_file(ctx, record).await {
+                Ok(()) => deleted += 1,
+                Err(err) => {
+                    tracing::warn!(record.file_name, "failed to delete expired upload: {}", err)
+                }
+            }
+        }
+
+        let Some((channel_id, message_id)) = channel_id.zip(message_id).filter(|_| deleted > 0)
+        else {
+            continue;
+        };
+
+        let result = MieEmbed::existing(ctx.clone(), channel_id, message_id)
+            .title("Expired".to_string())
+            .description(expiry.describe(deleted))
+            .send_or_update()
+            .await;
+        if let Err(err) = result {
+            tracing::debug!(%message_id, "failed to mark message as expired: {}", err);
+        }
+    }
+
+    Ok(())
+}
+
+type Message = (Id<ChannelMarker>, Id<MessageMarker>);
+
+/// Every message each file was posted in, the one it was recorded against
+/// and the ones it was attached to later.
+fn posted_in(
+    ctx: &AppContext,
+    records: &[MediaRecord],
+) -> anyhow::Result<HashMap<String, Vec<Message>>> {
+    let mut posted: HashMap<_, Vec<_>> = HashMap::new();
+    for record in records {
+        if let Some(message) = record.channel_id.zip(record.message_id) {
+            posted
+                .entry(record.file_name.clone())
+                .or_default()
+                .push(message);
+        }
+    }
+
+    for (message_id, attached) in ctx.media.all_attached()? {
+        let Some(channel_id) = attached.channel_id else {
+            continue;
+        };
+        for file_name in attached.file_names {
+            let messages = posted.entry(file_name).or_default();
+            if !messages.contains(&(channel_id, message_id)) {
+                messages.push((channel_id, message_id));
+            }
+        }
+    }
+
+    Ok(posted)
+}
+
+/// Whether any of the messages is pinned, remembering the answers in pins.
+async fn any_pinned(
+    ctx: &AppContext,
+    messages: &[Message],
+    pins: &mut HashMap<Id<MessageMarker>, bool>,
+) -> anyhow::Result<bool> {
+    for (channel_id, message_id) in messages {
+        let pinned = match pins.get(message_id) {
+            Some(pinned) => *pinned,
+            None => {
+                let pinned = is_pinned(ctx, *channel_id, *message_id).await?;
+                pins.insert(*message_id, pinned);
+                pinned
+            }
+        };
+        if pinned {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Uploads past the age limit, then the oldest uploads of every guild over
+/// its budget. Pinned uploads never expire and don't count towards the budget.
+fn expired_records(config: &Config, records: Vec<MediaRecord>) -> Vec<(MediaRecord, Expiry)> {
+    let mut expired = HashMap::new();
+
+    if let Some(days) = config.retention_days {
+        let cutoff = now().saturating_sub(days * 24 * 60 * 60);
+        for record in &records {
+            if !record.pinned && record.created_at < cutoff {
+                expired.insert(record.file_name.clone(), Expiry::Age(days));
+            }
+        }
+    }
+
+    if let Some(budget) = config.retention_guild_bytes {
+        let mut guilds: HashMap<_, Vec<_>> = HashMap::new();
+        for record in &records {
+            // DMs only expire by age
+            if let Some(guild_id) = record.guild_id {
+                guilds.entry(guild_id).or_default().push(record);
+            }
+        }
+
+        for mut uploads in guilds.into_values() {
+            uploads.sort_by_key(|record| std::cmp::Reverse(record.created_at));
+
+            let mut used = 0;
+            for record in uploads {
+                if record.pinned || expired.contains_key(&record.file_name) {
+                    continue;
+                }
+
+                used += record.size;
+                if used > budget {
+                    expired.insert(record.file_name.clone(), Expiry::GuildFull);
+                }
+            }
+        }
+    }
+
+    records
+        .into_iter()
+        .filter_map(|record| {
+            let expiry = *expired.get(&record.file_name)?;
+            Some((record, expiry))
+        })
+        .collect()
+}
+
+async fn is_pinned(
+    ctx: &AppContext,
+    channel_id: Id<ChannelMarker>,
+    message_id: Id<MessageMarker>,
+) -> anyhow::Result<bool> {
+    match ctx.http.message(channel_id, message_id).await {
+        Ok(response) => Ok(response.model().await?.pinned),
+        // Deleted messages can't be pinned
+        Err(err) if matches!(err.kind(), ErrorType::Response { status, .. } if status.get() == 404) => {
+            Ok(false)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+async fn delete_file(ctx: &AppContext, record: &MediaRecord) -> anyhow::Result<()> {
+    ctx.b2
+        .basic_client()
+        .delete_file_version(
+            B2DeleteFileVersionBody::builder()
+                .file_name(record.file_name.clone())
+                .file_id(record.file_id.clone())
+                .build(),
+        )
+        .await?;
+
+    ctx.media.remove(&record.file_name);
+    // The cached links would point at nothing now
+    if let Some(key) = &record.cache_key {
+        ctx.cache.remove(key);
+    }
+
+    tracing::info!(record.file_name, record.size, "deleted expired upload");
+    Ok(())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::test_config;
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    fn record(file_name: &str, guild_id: Option<u64>, size: u64, age_days: u64) -> MediaRecord {
+        MediaRecord {
+            file_id: String::new(),
+            file_name: file_name.to_string(),
+            url: String::new(),
+            size,
+            sha1: None,
+            source_url: String::new(),
+            title: None,
+            author: None,
+            author_url: None,
+            cache_key: None,
+            guild_id: guild_id.map(Id::new),
+            channel_id: None,
+            message_id: None,
+            uploader_id: Id::new(1),
+            pinned: false,
+            created_at: now() - age_days * DAY,
+        }
+    }
+
+    fn expired_names(config: &Config, records: Vec<MediaRecord>) -> Vec<String> {
+        let mut names = expired_records(config, records)
+            .into_iter()
+            .map(|(record, _)| record.file_name)
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn nothing_expires_without_a_policy() {
+        let records = vec![record("old", Some(1), 100, 1000)];
+        assert!(expired_records(&test_config(), records).is_empty());
+    }
+
+    #[test]
+    fn expires_by_age() {
+        let config = Config {
+            retention_days: Some(30),
+            ..test_config()
+        };
+        let mut pinned = record("pinned", Some(1), 100, 40);
+        pinned.pinned = true;
+        let records = vec![
+            record("old", Some(1), 100, 31),
+            record("dm", None, 100, 31),
+            record("new", Some(1), 100, 29),
+            pinned,
+        ];
+
+        assert_eq!(expired_names(&config, records), ["dm", "old"]);
+    }
+
+    #[test]
+    fn expires_the_oldest_past_the_guild_budget() {
+        let config = Config {
+            retention_guild_bytes: Some(250),
+            ..test_config()
+        };
+        let mut pinned = record("pinned", Some(1), 1000, 0);
+        pinned.pinned = true;
+        let records = vec![
+            record("newest", Some(1), 100, 1),
+            record("middle", Some(1), 100, 2),
+            record("oldest", Some(1), 100, 3),
+            record("other guild", Some(2), 200, 3),
+            record("dm", None, 1000, 3),
+            pinned,
+        ];
+
+        let expired = expired_records(&config, records);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].0.file_name, "oldest");
+        assert!(matches!(expired[0].1, Expiry::GuildFull));
+    }
+
+    #[test]
+    fn aged_out_uploads_free_up_the_budget() {
+        let config = Config {
+            retention_days: Some(30),
+            retention_guild_bytes: Some(200),
+            ..test_config()
+        };
+        let records = vec![
+            record("new", Some(1), 100, 1),
+            record("recent", Some(1), 100, 2),
+            record("old", Some(1), 100, 40),
+        ];
+
+        let expired = expired_records(&config, records);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].0.file_name, "old");
+        assert!(matches!(expired[0].1, Expiry::Age(30)));
+    }
+}
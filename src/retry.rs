@@ -0,0 +1,247 @@
+use std::error::Error;
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use backblaze_b2_client::error::B2Error;
+use backblaze_b2_client::tasks::upload::error::FileUploadError;
+use rand::Rng;
+
+use crate::errors::MieError;
+
+pub const MAX_ATTEMPTS: u32 = 3;
+const BASE_DELAY: Duration = Duration::from_secs(2);
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+// Bits of yt-dlp output that mean the network hiccuped rather than the
+// url being broken
+const TRANSIENT_OUTPUT: &[&str] = &[
+    "fragment",
+    "Connection reset",
+    "Connection aborted",
+    "Remote end closed connection",
+    "IncompleteRead",
+    "timed out",
+    "Temporary failure in name resolution",
+    "HTTP Error 429",
+    "HTTP Error 500",
+    "HTTP Error 502",
+    "HTTP Error 503",
+    "HTTP Error 504",
+];
+
+/// Whether trying the same thing again later has a chance of working.
+pub trait Transient {
+    fn is_transient(&self) -> bool;
+}
+
+impl Transient for MieError {
+    fn is_transient(&self) -> bool {
+        match self {
+            MieError::YtDlError(err) => is_transient_output(err),
+            MieError::DirectDownloadFailed(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Transient for Box<dyn Error + Send + Sync> {
+    fn is_transient(&self) -> bool {
+        if let Some(err) = self.downcast_ref::<MieError>() {
+            return err.is_transient();
+        }
+
+        if let Some(err) = self.downcast_ref::<FileUploadError>() {
+            return match err {
+                FileUploadError::RequestError(err) => is_transient_b2(err),
+                _ => false,
+            };
+        }
+
+        if let Some(err) = self.downcast_ref::<B2Error>() {
+            return is_transient_b2(err);
+        }
+
+        if let Some(err) = self.downcast_ref::<reqwest::Error>() {
+            return is_transient_reqwest(err);
+        }
+
+        if let Some(err) = self.downcast_ref::<std::io::Error>() {
+            return matches!(
+                err.kind(),
+                ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::TimedOut
+                    | ErrorKind::Interrupted
+            );
+        }
+
+        false
+    }
+}
+
+pub fn is_transient_output(output: &str) -> bool {
+    TRANSIENT_OUTPUT
+        .iter()
+        .any(|pattern| output.contains(pattern))
+}
+
+fn is_transient_status(status: u16) -> bool {
+    status == 408 || status == 429 || status >= 500
+}
+
+fn is_transient_b2(err: &B2Error) -> bool {
+    match err {
+        B2Error::RequestError(err) => is_transient_status(err.status.get()),
+        B2Error::RequestSendError(err) => is_transient_reqwest(err),
+        _ => false,
+    }
+}
+
+fn is_transient_reqwest(err: &reqwest::Error) -> bool {
+    match err.status() {
+        Some(status) => is_transient_status(status.as_u16()),
+        None => err.is_timeout() || err.is_connect() || err.is_request() || err.is_body(),
+    }
+}
+
+/// Keeps track of attempts at one stage, only transient errors are retried
+/// and each retry waits twice as long as the last one.
+#[derive(Debug)]
+pub struct Backoff {
+    attempt: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff { attempt: 1 }
+    }
+}
+
+impl Backoff {
+    /// Counts the failure, returns false when it's not worth trying again.
+    pub fn retry(&mut self, err: &impl Transient) -> bool {
+        if self.attempt >= MAX_ATTEMPTS || !err.is_transient() {
+            return false;
+        }
+
+        self.attempt += 1;
+        true
+    }
+
+    pub fn describe(&self) -> String {
+        format!("Retrying ({}/{})", self.attempt, MAX_ATTEMPTS)
+    }
+
+    pub async fn wait(&self) {
+        tokio::time::sleep(self.delay()).await;
+    }
+
+    fn delay(&self) -> Duration {
+        let delay = BASE_DELAY
+            .saturating_mul(2u32.saturating_pow(self.attempt.saturating_sub(2)))
+            .min(MAX_DELAY);
+
+        // Jitter so jobs that failed together don't all retry together
+        let jitter = rand::thread_rng().gen_range(0.5..1.0);
+        delay.mul_f64(jitter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn boxed(err: impl Error + Send + Sync + 'static) -> Box<dyn Error + Send + Sync> {
+        Box::new(err)
+    }
+
+    #[test]
+    fn delays_double_with_jitter_up_to_the_cap() {
+        let bounds = [
+            (2, Duration::from_secs(1), Duration::from_secs(2)),
+            (3, Duration::from_secs(2), Duration::from_secs(4)),
+            (4, Duration::from_secs(4), Duration::from_secs(8)),
+            (40, MAX_DELAY / 2, MAX_DELAY),
+        ];
+        for (attempt, min, max) in bounds {
+            let backoff = Backoff { attempt };
+            for _ in 0..100 {
+                let delay = backoff.delay();
+                assert!(
+                    delay >= min && delay < max,
+                    "{:?} on attempt {}",
+                    delay,
+                    attempt
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let err = MieError::DirectDownloadFailed("connection reset".to_string());
+        let mut backoff = Backoff::default();
+        for _ in 1..MAX_ATTEMPTS {
+            assert!(backoff.retry(&err));
+        }
+        assert!(!backoff.retry(&err));
+    }
+
+    #[test]
+    fn never_retries_permanent_errors() {
+        let mut backoff = Backoff::default();
+        assert!(!backoff.retry(&MieError::LiveStream));
+        assert_eq!(backoff.attempt, 1);
+    }
+
+    #[test]
+    fn classifies_ytdlp_output() {
+        let transient = [
+            "ERROR: [youtube] abc: Unable to download webpage: HTTP Error 503: Service Unavailable",
+            "ERROR: fragment 3 not found, unable to continue",
+            "ERROR: Remote end closed connection without response",
+            "ERROR: [Errno -3] Temporary failure in name resolution",
+        ];
+        for output in transient {
+            assert!(
+                MieError::YtDlError(output.to_string()).is_transient(),
+                "{}",
+                output
+            );
+        }
+
+        let permanent = [
+            "ERROR: [youtube] abc: Video unavailable",
+            "ERROR: Unsupported URL: https://example.com",
+            "ERROR: HTTP Error 404: Not Found",
+        ];
+        for output in permanent {
+            assert!(
+                !MieError::YtDlError(output.to_string()).is_transient(),
+                "{}",
+                output
+            );
+        }
+    }
+
+    #[test]
+    fn classifies_boxed_errors() {
+        assert!(boxed(MieError::DirectDownloadFailed(String::new())).is_transient());
+        assert!(!boxed(MieError::TooLong(4000.0)).is_transient());
+        assert!(boxed(std::io::Error::from(ErrorKind::ConnectionReset)).is_transient());
+        assert!(boxed(std::io::Error::from(ErrorKind::TimedOut)).is_transient());
+        assert!(!boxed(std::io::Error::from(ErrorKind::NotFound)).is_transient());
+        assert!(!Box::<dyn Error + Send + Sync>::from("something else").is_transient());
+    }
+
+    #[test]
+    fn only_some_statuses_are_transient() {
+        for status in [408, 429, 500, 502, 503] {
+            assert!(is_transient_status(status), "{}", status);
+        }
+        for status in [400, 401, 403, 404] {
+            assert!(!is_transient_status(status), "{}", status);
+        }
+    }
+}
@@ -0,0 +1,623 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::path::Path as FilePath;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::body::{Body, Bytes};
+use axum::extract::{ConnectInfo, Path, Query, State};
+use axum::http::header::{
+    ACCEPT_RANGES, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, LAST_MODIFIED,
+    RANGE,
+};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{Html, IntoResponse, Redirect, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::process::Command;
+use url::Url;
+
+use crate::crop::probe_dimensions;
+use crate::env::Config;
+use crate::links::{file_url, verify_page};
+use crate::media::MediaRecord;
+use crate::video::{content_type, MediaKind};
+use crate::AppContext;
+
+const PREVIEW_TIMEOUT: Duration = Duration::from_secs(20);
+// Previews are cheap to make again, just don't let them pile up forever
+const MAX_PREVIEWS: usize = 512;
+// Signed file urls are swapped for new ones this long before they run out
+const FILE_URL_MARGIN: Duration = Duration::from_secs(60);
+// Size given to players when the video couldn't be probed
+const DEFAULT_DIMENSIONS: (u32, u32) = (640, 360);
+// Headers passed through from storage when streaming a file
+const STREAM_HEADERS: &[axum::http::HeaderName] = &[
+    CONTENT_TYPE,
+    CONTENT_LENGTH,
+    CONTENT_RANGE,
+    ACCEPT_RANGES,
+    ETAG,
+    LAST_MODIFIED,
+    CACHE_CONTROL,
+];
+
+struct ServerState {
+    ctx: Arc<AppContext>,
+    client: reqwest::Client,
+    // Page name to video dimensions, probed the first time a page is asked for
+    dimensions: Mutex<HashMap<String, Option<(u32, u32)>>>,
+    thumbnails: Mutex<HashMap<String, Bytes>>,
+    // Signed urls to the files, so seeking doesn't ask b2 every time
+    file_urls: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+/// Signature handed out with private links, the page passes it on to the
+/// file and thumbnail it links to.
+#[derive(Debug, Default, Deserialize)]
+struct SignedQuery {
+    expires: Option<u64>,
+    signature: Option<String>,
+}
+
+impl SignedQuery {
+    fn from_url(url: &Url) -> Self {
+        let mut query = SignedQuery::default();
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "expires" => query.expires = value.parse().ok(),
+                "signature" => query.signature = Some(value.into_owned()),
+                _ => {}
+            }
+        }
+        query
+    }
+
+    fn verify(&self, config: &Config, name: &str) -> bool {
+        verify_page(config, name, self.expires, self.signature.as_deref())
+    }
+
+    // Only private links carry one, anything else sent along is dropped
+    fn to_query(&self, config: &Config) -> String {
+        match (self.expires, &self.signature) {
+            (Some(expires), Some(signature)) if config.private_links => {
+                format!("?expires={}&signature={}", expires, signature)
+            }
+            _ => String::new(),
+        }
+    }
+}
+
+impl ServerState {
+    fn record(&self, name: &str) -> Option<MediaRecord> {
+        let config = &self.ctx.config;
+        self.ctx
+            .media
+            .get(&format!("{}/{}", config.b2_bucket_path_prefix, name))
+    }
+
+    /// Where to fetch the file from, reusing the authorization while it lasts.
+    async fn file_url(&self, record: &MediaRecord) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let now = Instant::now();
+        if let Some((url, expires_at)) = self.file_urls.lock().unwrap().get(&record.file_name) {
+            if now + FILE_URL_MARGIN < *expires_at {
+                return Ok(url.clone());
+            }
+        }
+
+        let ctx = &self.ctx;
+        let url = file_url(&ctx.config, &ctx.b2.basic_client(), &record.file_name).await?;
+        let expires_at = now + Duration::from_secs(ctx.config.link_lifetime);
+
+        let mut file_urls = self.file_urls.lock().unwrap();
+        if file_urls.len() >= MAX_PREVIEWS {
+            file_urls.clear();
+        }
+        file_urls.insert(record.file_name.clone(), (url.clone(), expires_at));
+        Ok(url)
+    }
+}
+
+/// Where the server can be reached from outside, without a trailing slash.
+pub fn base_url(config: &Config) -> String {
+    match (&config.public_url, config.http_addr) {
+        (Some(public_url), _) => public_url.trim_end_matches('/').to_string(),
+        (None, Some(addr)) => format!("http://{}", addr),
+        (None, None) => String::new(),
+    }
+}
+
+/// Serves a landing page for every upload with the tags discord needs to
+/// embed it as a playable video, along with the file itself. Does nothing
+/// unless an address is configured.
+pub async fn serve(ctx: Arc<AppContext>) {
+    let Some(addr) = ctx.config.http_addr else {
+        return;
+    };
+
+    let state = Arc::new(ServerState {
+        ctx,
+        client: reqwest::Client::new(),
+        dimensions: Mutex::default(),
+        thumbnails: Mutex::default(),
+        file_urls: Mutex::default(),
+    });
+
+    let mut app = Router::new()
+        .route("/m/{name}", get(landing_page))
+        .route("/m/{name}/file", get(stream_file))
+        .route("/m/{name}/thumbnail.jpg", get(thumbnail))
+        .route("/oembed", get(oembed))
+        .nest("/api", crate::api::router(state.ctx.clone()));
+    // Logging in goes through the bot's application, which needs its secret
+    if state.ctx.config.discord_client_secret.is_some() {
+        app = app.merge(crate::gallery::router(state.ctx.clone()));
+    }
+    let app = app.with_state(state);
+
+    let listener = match tokio::net::TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            tracing::error!(%addr, "failed to start media server: {}", err);
+            return;
+        }
+    };
+
+    tracing::info!(%addr, "media server listening");
+    if let Err(err) = axum::serve(listener, app).await {
+        tracing::error!("media server stopped: {}", err);
+    }
+}
+
+/// Serves what only whoever runs the bot should see, on its own address so
+/// it isn't public along with the media server. Always runs, health checks
+/// shouldn't depend on the media server being configured.
+pub async fn serve_internal(ctx: Arc<AppContext>) {
+    let addr = ctx.config.metrics_addr;
+    let app = Router::new()
+        .route("/metrics", get(metrics))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .with_state(ctx)
+        .into_make_service_with_connect_info::<SocketAddr>();
+
+    let listener = match tokio::net::TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            tracing::error!(%addr, "failed to start metrics server: {}", err);
+            return;
+        }
+    };
+
+    tracing::info!(%addr, "metrics server listening");
+    if let Err(err) = axum::serve(listener, app).await {
+        tracing::error!("metrics server stopped: {}", err);
+    }
+}
+
+async fn landing_page(
+    State(state): State<Arc<ServerState>>,
+    Path(name): Path<String>,
+    Query(signed): Query<SignedQuery>,
+) -> Response {
+    if !signed.verify(&state.ctx.config, &name) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    let Some(record) = state.record(&name) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let kind = MediaKind::from_path(FilePath::new(&name));
+    let dimensions = match kind {
+        MediaKind::Video => dimensions(&state, &name, &record).await,
+        _ => None,
+    };
+
+    let query = signed.to_query(&state.ctx.config);
+    let page = format!("{}/m/{}", base_url(&state.ctx.config), name);
+    let page_url = format!("{}{}", page, query);
+    let file_url = escape(&format!("{}/file{}", page, query));
+    let thumbnail_url = escape(&format!("{}/thumbnail.jpg{}", page, query));
+    let title = escape(record.title.as_deref().unwrap_or(&name));
+
+    let mut tags = vec![
+        format!(r#"<meta property="og:title" content="{}">"#, title),
+        r#"<meta property="og:site_name" content="mie">"#.to_string(),
+        format!(
+            r#"<meta property="og:url" content="{}">"#,
+            escape(&page_url)
+        ),
+        r##"<meta name="theme-color" content="#b37cfa">"##.to_string(),
+        format!(
+            r#"<link rel="alternate" type="application/json+oembed" href="{}/oembed?url={}" title="{}">"#,
+            base_url(&state.ctx.config),
+            urlencoding::encode(&page_url),
+            title
+        ),
+    ];
+
+    let body = match kind {
+        MediaKind::Video => {
+            let content_type = content_type(FilePath::new(&name));
+            tags.extend([
+                r#"<meta property="og:type" content="video.other">"#.to_string(),
+                format!(r#"<meta property="og:image" content="{}">"#, thumbnail_url),
+                format!(r#"<meta property="og:video" content="{}">"#, file_url),
+                format!(r#"<meta property="og:video:url" content="{}">"#, file_url),
+                format!(
+                    r#"<meta property="og:video:secure_url" content="{}">"#,
+                    file_url
+                ),
+                format!(
+                    r#"<meta property="og:video:type" content="{}">"#,
+                    content_type
+                ),
+                r#"<meta name="twitter:card" content="player">"#.to_string(),
+                format!(
+                    r#"<meta name="twitter:player:stream" content="{}">"#,
+                    file_url
+                ),
+            ]);
+
+            if let Some((width, height)) = dimensions {
+                tags.extend([
+                    format!(r#"<meta property="og:video:width" content="{}">"#, width),
+                    format!(r#"<meta property="og:video:height" content="{}">"#, height),
+                    format!(r#"<meta name="twitter:player:width" content="{}">"#, width),
+                    format!(
+                        r#"<meta name="twitter:player:height" content="{}">"#,
+                        height
+                    ),
+                ]);
+            }
+
+            format!(
+                r#"<video src="{}" poster="{}" controls autoplay></video>"#,
+                file_url, thumbnail_url
+            )
+        }
+        _ => {
+            tags.extend([
+                r#"<meta property="og:type" content="website">"#.to_string(),
+                format!(r#"<meta property="og:image" content="{}">"#, file_url),
+                r#"<meta name="twitter:card" content="summary_large_image">"#.to_string(),
+            ]);
+
+            format!(r#"<img src="{}" alt="{}">"#, file_url, title)
+        }
+    };
+
+    Html(format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+{tags}
+<style>body{{margin:0;background:#000;display:flex;flex-direction:column;align-items:center;justify-content:center;min-height:100vh;font-family:sans-serif}}video,img{{max-width:100%;max-height:90vh}}a{{color:#b37cfa}}</style>
+</head>
+<body>
+{body}
+<p><a href="{source}" rel="noreferrer">Source</a></p>
+</body>
+</html>
+"#,
+        title = title,
+        tags = tags.join("\n"),
+        body = body,
+        source = escape(&record.source_url),
+    ))
+    .into_response()
+}
+
+async fn metrics(State(ctx): State<Arc<AppContext>>) -> Response {
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        crate::metrics::render(&ctx),
+    )
+        .into_response()
+}
+
+/// Fails once the gateway has gone quiet, the watchdog exits soon after.
+async fn healthz(State(ctx): State<Arc<AppContext>>) -> Response {
+    let health = &ctx.health;
+    let status = match health.is_live(&ctx.config) {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    let body = json!({
+        "gateway_connected": health.connected(),
+        "last_event_secs_ago": health.silent_for(),
+    });
+    (status, Json(body)).into_response()
+}
+
+/// Fails while jobs can't go through, disconnected or a broken dependency.
+/// Only callers on the same machine get told what's broken.
+async fn readyz(
+    State(ctx): State<Arc<AppContext>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+) -> Response {
+    let mut readiness = ctx.health.readiness(&ctx).await;
+    if !peer.ip().is_loopback() {
+        readiness = readiness.without_details();
+    }
+    let status = match readiness.ready() {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    (status, Json(readiness)).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct OEmbedQuery {
+    url: String,
+    format: Option<String>,
+    maxwidth: Option<u32>,
+    maxheight: Option<u32>,
+}
+
+/// Describes a landing page (or its file) for consumers that speak oEmbed.
+async fn oembed(
+    State(state): State<Arc<ServerState>>,
+    Query(query): Query<OEmbedQuery>,
+) -> Response {
+    if query
+        .format
+        .as_deref()
+        .is_some_and(|format| format != "json")
+    {
+        return StatusCode::NOT_IMPLEMENTED.into_response();
+    }
+
+    // Only our own pages, /m/{name} with anything after it
+    let name = Url::parse(&query.url).ok().and_then(|url| {
+        let mut segments = url.path_segments()?;
+        match (segments.next(), segments.next()) {
+            (Some("m"), Some(name)) => Some((name.to_string(), SignedQuery::from_url(&url))),
+            _ => None,
+        }
+    });
+    let Some((name, signed)) = name else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if !signed.verify(&state.ctx.config, &name) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let Some(record) = state.record(&name) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let kind = MediaKind::from_path(FilePath::new(&name));
+    let probed = dimensions(&state, &name, &record).await;
+    let (width, height) = fit(
+        probed.unwrap_or(DEFAULT_DIMENSIONS),
+        query.maxwidth,
+        query.maxheight,
+    );
+
+    let base_url = base_url(&state.ctx.config);
+    let query = signed.to_query(&state.ctx.config);
+    let page_url = format!("{}/m/{}", base_url, name);
+    let file_url = format!("{}/file{}", page_url, query);
+    let thumbnail_url = format!("{}/thumbnail.jpg{}", page_url, query);
+
+    let mut response = json!({
+        "version": "1.0",
+        "title": record.title.as_deref().unwrap_or(&name),
+        "provider_name": "mie",
+        "provider_url": base_url,
+        "width": width,
+        "height": height,
+    });
+
+    if let Some(author) = &record.author {
+        response["author_name"] = json!(author);
+    }
+    if let Some(author_url) = &record.author_url {
+        response["author_url"] = json!(author_url);
+    }
+
+    match kind {
+        MediaKind::Video => {
+            response["type"] = json!("video");
+            response["html"] = json!(format!(
+                r#"<video src="{}" poster="{}" width="{}" height="{}" controls></video>"#,
+                escape(&file_url),
+                escape(&thumbnail_url),
+                width,
+                height
+            ));
+
+            // Same scaling ffmpeg does for the thumbnail
+            if let Some((video_width, video_height)) = probed {
+                let thumbnail_width = video_width.min(640);
+                let thumbnail_height = video_height * thumbnail_width / video_width.max(1);
+                response["thumbnail_url"] = json!(thumbnail_url);
+                response["thumbnail_width"] = json!(thumbnail_width);
+                response["thumbnail_height"] = json!(thumbnail_height);
+            }
+        }
+        _ => {
+            response["type"] = json!("photo");
+            response["url"] = json!(file_url);
+        }
+    }
+
+    Json(response).into_response()
+}
+
+/// Scales down to fit inside the consumer's limits, keeping the aspect ratio.
+fn fit((width, height): (u32, u32), max_width: Option<u32>, max_height: Option<u32>) -> (u32, u32) {
+    let mut scale = 1.0_f64;
+    if let Some(max_width) = max_width.filter(|max| *max < width) {
+        scale = scale.min(max_width as f64 / width as f64);
+    }
+    if let Some(max_height) = max_height.filter(|max| *max < height) {
+        scale = scale.min(max_height as f64 / height as f64);
+    }
+
+    (
+        (width as f64 * scale).round() as u32,
+        (height as f64 * scale).round() as u32,
+    )
+}
+
+/// Streams the file from storage, passing ranges through so players can seek.
+async fn stream_file(
+    State(state): State<Arc<ServerState>>,
+    Path(name): Path<String>,
+    Query(signed): Query<SignedQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if !signed.verify(&state.ctx.config, &name) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    let Some(record) = state.record(&name) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let url = match state.file_url(&record).await {
+        Ok(url) => url,
+        Err(err) => {
+            tracing::error!(name, "failed to get file url: {}", err);
+            return StatusCode::BAD_GATEWAY.into_response();
+        }
+    };
+
+    let mut request = state.client.get(url);
+    if let Some(range) = headers.get(RANGE) {
+        request = request.header(RANGE, range);
+    }
+
+    let upstream = match request.send().await {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            tracing::error!(name, "failed to fetch file from storage: {}", err);
+            return StatusCode::BAD_GATEWAY.into_response();
+        }
+    };
+
+    let mut response = Response::builder().status(upstream.status());
+    for header in STREAM_HEADERS {
+        if let Some(value) = upstream.headers().get(header) {
+            response = response.header(header, value);
+        }
+    }
+
+    response
+        .body(Body::from_stream(upstream.bytes_stream()))
+        .unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())
+}
+
+async fn thumbnail(
+    State(state): State<Arc<ServerState>>,
+    Path(name): Path<String>,
+    Query(signed): Query<SignedQuery>,
+) -> Response {
+    if !signed.verify(&state.ctx.config, &name) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    let Some(record) = state.record(&name) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    // Images are their own thumbnail
+    if MediaKind::from_path(FilePath::new(&name)) != MediaKind::Video {
+        return Redirect::temporary(&format!(
+            "/m/{}/file{}",
+            name,
+            signed.to_query(&state.ctx.config)
+        ))
+        .into_response();
+    }
+
+    if let Some(thumbnail) = state.thumbnails.lock().unwrap().get(&name) {
+        return ([(CONTENT_TYPE, "image/jpeg")], thumbnail.clone()).into_response();
+    }
+
+    let thumbnail = match state.file_url(&record).await {
+        Ok(url) => tokio::time::timeout(PREVIEW_TIMEOUT, extract_thumbnail(&url))
+            .await
+            .ok()
+            .flatten(),
+        Err(err) => {
+            tracing::error!(name, "failed to get file url: {}", err);
+            None
+        }
+    };
+
+    let Some(thumbnail) = thumbnail else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let mut thumbnails = state.thumbnails.lock().unwrap();
+    if thumbnails.len() >= MAX_PREVIEWS {
+        thumbnails.clear();
+    }
+    thumbnails.insert(name, thumbnail.clone());
+
+    ([(CONTENT_TYPE, "image/jpeg")], thumbnail).into_response()
+}
+
+async fn dimensions(state: &ServerState, name: &str, record: &MediaRecord) -> Option<(u32, u32)> {
+    if let Some(dimensions) = state.dimensions.lock().unwrap().get(name) {
+        return *dimensions;
+    }
+
+    let url = state.file_url(record).await.ok()?;
+    let dimensions = match tokio::time::timeout(PREVIEW_TIMEOUT, probe_dimensions(&url)).await {
+        Ok(Ok(dimensions)) => Some(dimensions),
+        Ok(Err(err)) => {
+            tracing::warn!(name, "failed to probe dimensions: {}", err);
+            None
+        }
+        Err(_) => None,
+    };
+
+    let mut cache = state.dimensions.lock().unwrap();
+    if cache.len() >= MAX_PREVIEWS {
+        cache.clear();
+    }
+    cache.insert(name.to_string(), dimensions);
+    dimensions
+}
+
+/// Picks a representative frame from the start of the video, ffmpeg only
+/// reads as much of the file as it needs.
+async fn extract_thumbnail(url: &str) -> Option<Bytes> {
+    let output = Command::new("ffmpeg")
+        .args(["-v", "error", "-i", url])
+        .args(["-vf", "thumbnail,scale='min(640,iw)':-2"])
+        .args(["-frames:v", "1", "-f", "image2", "-c:v", "mjpeg", "pipe:1"])
+        .kill_on_drop(true)
+        .output()
+        .await
+        .ok()?;
+
+    if !output.status.success() || output.stdout.is_empty() {
+        tracing::warn!(
+            "failed to extract thumbnail: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        return None;
+    }
+
+    Some(Bytes::from(output.stdout))
+}
+
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
@@ -0,0 +1,320 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use twilight_model::id::marker::UserMarker;
+use twilight_model::id::Id;
+use url::Url;
+
+use crate::cache::{cache_key, CachedResult};
+use crate::env::Config;
+use crate::errors::MieError;
+use crate::media::MediaOrigin;
+use crate::metrics::{self, job_finished, Outcome};
+use crate::probe::{plan_download, DownloadPlan};
+use crate::quota::check_quota;
+use crate::retry::Backoff;
+use crate::upload::{require_any, UploadBatch, UploadFile, UploadedFile};
+use crate::video::{download_video, DownloadedVideo, MediaKind, Quality};
+use crate::AppContext;
+
+type DynamicResult<T = ()> = Result<T, Box<dyn Error + Send + Sync>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Probe,
+    Download,
+    Transcode,
+    Upload,
+}
+
+impl Stage {
+    pub fn timeout(&self, config: &Config) -> Duration {
+        Duration::from_secs(match self {
+            Stage::Probe => config.probe_timeout,
+            Stage::Download => config.download_timeout,
+            Stage::Transcode => config.transcode_timeout,
+            Stage::Upload => config.upload_timeout,
+        })
+    }
+}
+
+impl Display for Stage {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            Stage::Probe => write!(f, "probe"),
+            Stage::Download => write!(f, "download"),
+            Stage::Transcode => write!(f, "transcode"),
+            Stage::Upload => write!(f, "upload"),
+        }
+    }
+}
+
+/// Runs one stage of a job against its deadline. Running out of time cancels
+/// the rest of the job and drops the stage's future, child processes are all
+/// spawned with kill_on_drop so they go down along with it.
+pub async fn run_stage<T, E, F>(
+    config: &Config,
+    stage: Stage,
+    cancel: &CancellationToken,
+    future: F,
+) -> Result<T, E>
+where
+    F: Future<Output = Result<T, E>>,
+    E: From<MieError>,
+{
+    if cancel.is_cancelled() {
+        return Err(MieError::Cancelled.into());
+    }
+
+    let timeout = stage.timeout(config);
+    let start = Instant::now();
+    let result = tokio::select! {
+        result = future => result,
+        _ = tokio::time::sleep(timeout) => {
+            tracing::warn!(%stage, "timed out after {}s", timeout.as_secs());
+            cancel.cancel();
+            Err(MieError::TimedOut(stage, timeout.as_secs()).into())
+        }
+        _ = cancel.cancelled() => Err(MieError::Cancelled.into()),
+    };
+
+    metrics::stage_finished(stage, result.is_ok(), start.elapsed());
+    result
+}
+
+/// Where a job shows how it's getting on.
+pub trait Progress: Send {
+    /// Only called by mirror_link, the other helpers leave it to the caller.
+    fn started(&mut self, _stage: Stage) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    fn retrying(&mut self, stage: Stage, backoff: &Backoff) -> impl Future<Output = ()> + Send;
+}
+
+/// Retries without telling anyone, for jobs that only report how they ended.
+pub struct Quiet;
+
+impl Progress for Quiet {
+    async fn retrying(&mut self, _: Stage, _: &Backoff) {}
+}
+
+/// Downloads the link, going again for as long as the failures look like
+/// network trouble.
+pub async fn download_with_retry(
+    ctx: &AppContext,
+    url: &str,
+    quality: Quality,
+    estimated_size: Option<u64>,
+    cancel: &CancellationToken,
+    progress: &mut impl Progress,
+) -> Result<DownloadedVideo, MieError> {
+    let url = url.to_string();
+    let mut backoff = Backoff::default();
+    loop {
+        let result = run_stage(
+            &ctx.config,
+            Stage::Download,
+            cancel,
+            download_video(ctx, &url, quality, estimated_size),
+        )
+        .await;
+
+        match result {
+            Err(err) if backoff.retry(&err) => {
+                tracing::warn!(url, "download failed, retrying: {}", err);
+                progress.retrying(Stage::Download, &backoff).await;
+                backoff.wait().await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Uploads the files, going again for the ones that failed with a transient
+/// error. Fails when none of them made it up, otherwise the results line up
+/// with the files.
+pub async fn upload_with_retry(
+    ctx: &AppContext,
+    files: Vec<UploadFile>,
+    cancel: &CancellationToken,
+    progress: &mut impl Progress,
+) -> DynamicResult<Vec<DynamicResult<UploadedFile>>> {
+    let mut batch = UploadBatch::new(files);
+    let mut backoff = Backoff::default();
+    loop {
+        run_stage(
+            &ctx.config,
+            Stage::Upload,
+            cancel,
+            batch.upload(&ctx.config, ctx.b2.clone(), cancel),
+        )
+        .await?;
+
+        if !batch.retry(&mut backoff) {
+            return require_any(batch.finish());
+        }
+
+        tracing::warn!("upload failed, retrying");
+        progress.retrying(Stage::Upload, &backoff).await;
+        backoff.wait().await;
+    }
+}
+
+/// The files of a download that made it into the bucket.
+pub struct Mirrored {
+    pub uploaded: Vec<UploadedFile>,
+    pub links: Vec<String>,
+    // Links that are images, shown in the embed for galleries
+    pub images: Vec<String>,
+    // Files that didn't make it up, left out of the rest
+    pub failed: usize,
+    pub upload_time: u128,
+}
+
+impl Mirrored {
+    /// Records the files against where they were asked for and remembers
+    /// the links for the next time someone shares the same post.
+    pub fn save(
+        &self,
+        ctx: &AppContext,
+        origin: &MediaOrigin,
+        video: &DownloadedVideo,
+        cache_key: Option<&str>,
+    ) -> CachedResult {
+        ctx.media.record(origin, video, cache_key, &self.uploaded);
+
+        let cached = CachedResult::new(
+            self.links.clone(),
+            self.uploaded
+                .iter()
+                .map(|uploaded| uploaded.file_name.clone())
+                .collect(),
+            self.images.clone(),
+            video.is_gallery(),
+            video.download_time,
+            self.upload_time,
+        );
+        if let Some(key) = cache_key {
+            ctx.cache.insert(key, &cached);
+        }
+        cached
+    }
+}
+
+/// Uploads every file of the download, see upload_with_retry.
+pub async fn upload_video(
+    ctx: &AppContext,
+    video: &DownloadedVideo,
+    uploader_id: Id<UserMarker>,
+    cancel: &CancellationToken,
+    progress: &mut impl Progress,
+) -> DynamicResult<Mirrored> {
+    let start = Instant::now();
+    let results = upload_with_retry(ctx, video.uploads(uploader_id), cancel, progress).await?;
+    let upload_time = start.elapsed().as_millis();
+    let failed = results.iter().filter(|result| result.is_err()).count();
+
+    let (uploaded, kinds): (Vec<_>, Vec<_>) = video
+        .files
+        .iter()
+        .zip(results)
+        .filter_map(|(file, result)| result.ok().map(|uploaded| (uploaded, file.kind)))
+        .unzip();
+    let links = uploaded
+        .iter()
+        .map(|uploaded| uploaded.url.clone())
+        .collect::<Vec<_>>();
+    let images = links
+        .iter()
+        .zip(kinds)
+        .filter(|(_, kind)| *kind == MediaKind::Image)
+        .map(|(link, _)| link.clone())
+        .collect();
+
+    tracing::info!(
+        video.og_url,
+        failed,
+        "uploading complete in {}ms",
+        upload_time
+    );
+    Ok(Mirrored {
+        uploaded,
+        links,
+        images,
+        failed,
+        upload_time,
+    })
+}
+
+/// Every stage a link goes through when nobody needs to see them one by
+/// one, for jobs that only care about the links at the end. Reuses the
+/// files from last time when the post was mirrored before.
+pub async fn mirror_link(
+    ctx: &AppContext,
+    url: &Url,
+    origin: &MediaOrigin,
+    progress: &mut impl Progress,
+) -> DynamicResult<CachedResult> {
+    let cache_key = cache_key(&ctx.config, url, origin.guild_id, origin.uploader_id).await;
+    let cached = ctx
+        .cache
+        .get_signed(&cache_key, &ctx.config, &ctx.b2.basic_client())
+        .await;
+    if let Some(cached) = cached {
+        tracing::info!(%url, cache_key, "using cached result");
+        job_finished(url.as_str(), Outcome::Cached);
+        return Ok(cached);
+    }
+
+    // Cancelled as soon as any stage runs out of time
+    let cancel = CancellationToken::new();
+
+    progress.started(Stage::Probe).await;
+    let plan = run_stage(
+        &ctx.config,
+        Stage::Probe,
+        &cancel,
+        plan_download(&ctx.config, url),
+    )
+    .await?;
+    let (quality, estimated_size) = match plan {
+        DownloadPlan::Playlist(_) => return Err("Playlists can't be mirrored from here".into()),
+        DownloadPlan::Download(quality, estimated_size) => (quality, estimated_size),
+    };
+
+    progress.started(Stage::Download).await;
+    let downloaded_video = download_with_retry(
+        ctx,
+        url.as_str(),
+        quality,
+        estimated_size,
+        &cancel,
+        progress,
+    )
+    .await?;
+
+    check_quota(
+        ctx,
+        origin.guild_id,
+        origin.uploader_id,
+        downloaded_video.size(),
+    )?;
+
+    progress.started(Stage::Upload).await;
+    let mirrored = upload_video(
+        ctx,
+        &downloaded_video,
+        origin.uploader_id,
+        &cancel,
+        progress,
+    )
+    .await?;
+
+    job_finished(url.as_str(), Outcome::Success);
+    Ok(mirrored.save(ctx, origin, &downloaded_video, Some(&cache_key)))
+}
@@ -1,49 +1,498 @@
 use backblaze_b2_client::client::B2Client;
+use backblaze_b2_client::definitions::bodies::{B2FinishLargeFileBody, B2StartLargeFileUploadBody};
+use backblaze_b2_client::definitions::headers::B2UploadPartHeaders;
+use backblaze_b2_client::definitions::query_params::B2ListUnfinishedLargeFilesQueryParameters;
 use backblaze_b2_client::definitions::shared::B2File;
-use std::{env, error::Error, path::Path, sync::Arc};
+use backblaze_b2_client::simple_client::B2SimpleClient;
+use backblaze_b2_client::tasks::upload::{B2FileUploadSettings, FileUploadOptions};
+use futures::{stream, StreamExt, TryStreamExt};
+use serde::Deserialize;
+use sha1_smol::Sha1;
+use std::collections::{BTreeMap, HashMap};
+use std::io::SeekFrom;
+use std::sync::Mutex;
+use std::{error::Error, path::Path, sync::Arc};
 use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::sync::CancellationToken;
+
+use crate::env::Config;
+use crate::links::public_link;
+use crate::metrics;
+use crate::retry::{Backoff, Transient};
 
 type DynamicResult<T = ()> = Result<T, Box<dyn Error + Send + Sync>>;
 
-#[derive(Debug)]
+// Files above this go up in parts instead of a single request
+const LARGE_FILE_CUTOFF: u64 = 50_000_000;
+// B2 wants at least 5MB per part, all but the last one
+const PART_SIZE: u64 = 25_000_000;
+const PARALLEL_PARTS: usize = 4;
+// How much of the file is read at a time when hashing all of it
+const HASH_CHUNK: usize = 1_000_000;
+// Uploaded names are never reused, so caches can hold on to them forever
+const CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+#[derive(Debug, Clone)]
 pub struct UploadFile {
     pub path: String,
+    // Let b2 guess the content type when not set
+    pub content_type: Option<String>,
+    // Name browsers save the file as, the random one is used when not set
+    pub download_name: Option<String>,
+    // Custom file info, e.g. where the file came from
+    pub info: HashMap<String, String>,
 }
 
-// TODO: setup parallel uploads again (or completely remove it?)
-pub async fn upload_files<F>(
-    client: Arc<B2Client>,
-    bucket_id: Arc<str>,
-    files: Vec<UploadFile>,
-    _: Option<F>,
-) -> DynamicResult<Vec<DynamicResult<B2File>>>
-where
-    F: Fn(&str, u64, u64, f32, u64, u64) + Send + Sync + 'static,
-{
-    let results = vec![];
+impl UploadFile {
+    fn content_disposition(&self) -> Option<String> {
+        // Inline so the cdn plays videos in the browser instead of downloading them
+        self.download_name
+            .as_ref()
+            .map(|name| format!("inline; filename=\"{}\"", name))
+    }
+}
+
+/// A file that made it into the bucket.
+#[derive(Debug, Clone)]
+pub struct UploadedFile {
+    pub file_id: String,
+    // Key in the bucket, prefix included
+    pub file_name: String,
+    pub size: u64,
+    // B2 doesn't keep one for files uploaded in parts
+    pub sha1: Option<String>,
+    pub url: String,
+}
+
+/// Large files that were started but not finished, by file id. Kept so
+/// they can be cancelled once nothing is going to resume them.
+type Unfinished = Arc<Mutex<HashMap<String, String>>>;
+
+/// Uploads every file, a file failing doesn't stop the rest. The results
+/// come back in the same order as the files.
+async fn upload_files(
+    config: &Config,
+    client: &B2Client,
+    files: &[UploadFile],
+    unfinished: &Unfinished,
+    cancel: &CancellationToken,
+) -> DynamicResult<Vec<DynamicResult<UploadedFile>>> {
+    let mut results = vec![];
     for file in files {
-        let path = Path::new(&file.path);
-        let open_file = File::open(&file.path).await?;
-        let file_metadata = open_file.metadata().await?;
-        let file_size = file_metadata.len();
-        let file_path_prefix = env::var("B2_BUCKET_PATH_PREFIX").unwrap_or_else(|_| String::new());
-
-        let file_name = match path.file_name() {
-            Some(name) => file_path_prefix + "/" + &name.to_string_lossy(),
-            None => return Err(Box::from("Given file path is a folder.")),
+        let result = upload_file(config, client, file, unfinished, cancel).await;
+
+        // Whatever is left would just be cancelled too
+        if cancel.is_cancelled() {
+            return Err(Box::from("Upload was cancelled."));
+        }
+
+        match &result {
+            Ok(uploaded) => {
+                metrics::uploaded(uploaded.size);
+                tracing::debug!(
+                    uploaded.file_id,
+                    uploaded.file_name,
+                    uploaded.size,
+                    sha1 = uploaded.sha1,
+                    "uploaded file"
+                )
+            }
+            Err(err) => tracing::error!(file.path, "failed to upload file: {}", err),
+        }
+        results.push(result);
+    }
+    Ok(results)
+}
+
+/// Keeps the results of a batch across retries, so each retry only sends
+/// the files that failed with a transient error. Large files left unfinished
+/// are cancelled when the batch is dropped, whether it ran out of retries or
+/// its stage timed out.
+pub struct UploadBatch {
+    files: Vec<UploadFile>,
+    results: Vec<Option<DynamicResult<UploadedFile>>>,
+    client: Option<Arc<B2Client>>,
+    unfinished: Unfinished,
+}
+
+impl UploadBatch {
+    pub fn new(files: Vec<UploadFile>) -> Self {
+        let results = files.iter().map(|_| None).collect();
+        UploadBatch {
+            files,
+            results,
+            client: None,
+            unfinished: Unfinished::default(),
+        }
+    }
+
+    /// Uploads the files that don't have a result yet.
+    pub async fn upload(
+        &mut self,
+        config: &Config,
+        client: Arc<B2Client>,
+        cancel: &CancellationToken,
+    ) -> DynamicResult {
+        let pending = (0..self.files.len())
+            .filter(|&index| self.results[index].is_none())
+            .collect::<Vec<_>>();
+        let files = pending
+            .iter()
+            .map(|&index| self.files[index].clone())
+            .collect::<Vec<_>>();
+
+        // Set before uploading, a timed out stage drops this future midway
+        let client = self.client.insert(client).clone();
+        let results = upload_files(config, &client, &files, &self.unfinished, cancel).await?;
+        for (index, result) in pending.into_iter().zip(results) {
+            self.results[index] = Some(result);
+        }
+        Ok(())
+    }
+
+    /// Queues the transiently failed files up for the next upload, false when
+    /// none of the failures are worth trying again.
+    pub fn retry(&mut self, backoff: &mut Backoff) -> bool {
+        let transient = self
+            .results
+            .iter()
+            .find(|result| matches!(result, Some(Err(err)) if err.is_transient()));
+        let Some(Some(Err(err))) = transient else {
+            return false;
+        };
+
+        if !backoff.retry(err) {
+            return false;
+        }
+
+        for result in &mut self.results {
+            if matches!(result, Some(Err(err)) if err.is_transient()) {
+                *result = None;
+            }
+        }
+        true
+    }
+
+    pub fn finish(mut self) -> Vec<DynamicResult<UploadedFile>> {
+        std::mem::take(&mut self.results)
+            .into_iter()
+            .map(|result| result.unwrap_or_else(|| Err(Box::from("File was never uploaded."))))
+            .collect()
+    }
+}
+
+impl Drop for UploadBatch {
+    fn drop(&mut self) {
+        let unfinished = std::mem::take(&mut *self.unfinished.lock().unwrap());
+        let Some(client) = self.client.take().filter(|_| !unfinished.is_empty()) else {
+            return;
+        };
+
+        // Drop can't wait on b2, the parts are only storage until then
+        tokio::spawn(async move {
+            let b2 = client.basic_client();
+            for (file_id, file_name) in unfinished {
+                match b2.cancel_large_file(file_id).await {
+                    Ok(_) => tracing::info!(file_name, "cancelled unfinished large file"),
+                    Err(err) => {
+                        tracing::warn!(file_name, "failed to cancel large file: {}", err)
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Turns a batch where nothing made it up into the first file's error,
+/// partial failures are left for the caller to report.
+pub fn require_any(
+    results: Vec<DynamicResult<UploadedFile>>,
+) -> DynamicResult<Vec<DynamicResult<UploadedFile>>> {
+    if !results.iter().all(Result::is_err) {
+        return Ok(results);
+    }
+
+    match results.into_iter().next() {
+        Some(Err(err)) => Err(err),
+        _ => Ok(vec![]),
+    }
+}
+
+async fn upload_file(
+    config: &Config,
+    client: &B2Client,
+    file: &UploadFile,
+    unfinished: &Unfinished,
+    cancel: &CancellationToken,
+) -> DynamicResult<UploadedFile> {
+    let open_file = File::open(&file.path).await?;
+    let file_size = open_file.metadata().await?.len();
+
+    let file_name = match Path::new(&file.path).file_name() {
+        Some(name) => format!(
+            "{}/{}",
+            config.b2_bucket_path_prefix,
+            name.to_string_lossy()
+        ),
+        None => return Err(Box::from("Given file path is a folder.")),
+    };
+
+    let bucket_id = &config.b2_bucket_id;
+    // B2 has no sha1 of its own for large files, only the one we give it
+    let mut large_file_sha1 = None;
+    let uploaded = if file_size > LARGE_FILE_CUTOFF {
+        let (uploaded, sha1) = upload_large_file(
+            client, bucket_id, &file_name, file, file_size, unfinished, cancel,
+        )
+        .await?;
+        large_file_sha1 = Some(sha1);
+        uploaded
+    } else {
+        let options = FileUploadOptions {
+            options: B2FileUploadSettings {
+                content_type: file.content_type.clone().unwrap_or("b2/x-auto".to_string()),
+                b2_content_disposition: file.content_disposition(),
+                b2_cache_control: Some(CACHE_CONTROL.to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
         };
 
+        // Sent as headers, which b2 wants percent encoded
+        let info = file
+            .info
+            .iter()
+            .map(|(key, value)| (key.clone(), urlencoding::encode(value).into_owned()))
+            .collect();
+
         let upload = client
             .create_upload(
                 open_file,
                 file_name,
-                bucket_id.clone().to_string(),
-                None,
+                bucket_id.clone(),
+                Some(info),
                 file_size,
-                None,
+                Some(options),
             )
             .await;
-        upload.start().await?;
+
+        tokio::select! {
+            result = upload.start() => result?,
+            _ = cancel.cancelled() => {
+                upload.abort().await;
+                return Err(Box::from("Upload was cancelled."));
+            }
+        }
+    };
+
+    Ok(UploadedFile {
+        url: public_link(config, &client.basic_client(), &uploaded.file_name).await?,
+        file_id: uploaded.file_id,
+        file_name: uploaded.file_name,
+        size: uploaded.content_length,
+        sha1: uploaded
+            .content_sha1
+            .filter(|sha1| sha1 != "none")
+            .or(large_file_sha1),
+    })
+}
+
+/// Uploads the file in parts, several at a time. Parts that made it up in an
+/// earlier attempt at the same file are skipped, so a retry only sends what
+/// is missing. The file stays in unfinished until b2 has put it together.
+/// Returns the file's sha1 along with it, b2 doesn't have one for large files.
+async fn upload_large_file(
+    client: &B2Client,
+    bucket_id: &str,
+    file_name: &str,
+    file: &UploadFile,
+    file_size: u64,
+    unfinished: &Unfinished,
+    cancel: &CancellationToken,
+) -> DynamicResult<(B2File, String)> {
+    let b2 = client.basic_client();
+    let sha1 = file_sha1(&file.path).await?;
+
+    let (file_id, mut sha1s) = match find_unfinished(&b2, bucket_id, file_name).await {
+        Some(file_id) => {
+            let sha1s = list_parts(&b2, &file_id).await?;
+            tracing::info!(file_name, parts = sha1s.len(), "resuming large file upload");
+            (file_id, sha1s)
+        }
+        None => {
+            // Large files take the b2 headers as file info instead
+            let mut info = file.info.clone();
+            info.insert("b2-cache-control".to_string(), CACHE_CONTROL.to_string());
+            info.insert("large_file_sha1".to_string(), sha1.clone());
+            if let Some(disposition) = file.content_disposition() {
+                info.insert("b2-content-disposition".to_string(), disposition);
+            }
+
+            let body = B2StartLargeFileUploadBody::builder()
+                .bucket_id(bucket_id.to_string())
+                .file_name(file_name.to_string())
+                .content_type(file.content_type.clone().unwrap_or("b2/x-auto".to_string()))
+                .file_info(Some(info))
+                .build();
+            (b2.start_large_file(body).await?.file_id, BTreeMap::new())
+        }
+    };
+    unfinished
+        .lock()
+        .unwrap()
+        .insert(file_id.clone(), file_name.to_string());
+
+    let part_count = file_size.div_ceil(PART_SIZE) as u16;
+    let missing = (1..=part_count)
+        .filter(|part_number| !sha1s.contains_key(part_number))
+        .collect::<Vec<_>>();
+
+    let uploads = stream::iter(missing)
+        .map(|part_number| upload_part(&b2, &file_id, &file.path, file_size, part_number))
+        .buffer_unordered(PARALLEL_PARTS)
+        .try_collect::<Vec<_>>();
+
+    let uploaded = tokio::select! {
+        result = uploads => result?,
+        _ = cancel.cancelled() => {
+            // Nothing is going to resume it, don't leave the parts lying around
+            unfinished.lock().unwrap().remove(&file_id);
+            b2.cancel_large_file(file_id).await.ok();
+            return Err(Box::from("Upload was cancelled."));
+        }
+    };
+    sha1s.extend(uploaded);
+
+    let file = b2
+        .finish_large_file(B2FinishLargeFileBody {
+            file_id: file_id.clone(),
+            part_sha1_array: sha1s.into_values().collect(),
+        })
+        .await?;
+    unfinished.lock().unwrap().remove(&file_id);
+
+    Ok((file, sha1))
+}
+
+async fn upload_part(
+    b2: &B2SimpleClient,
+    file_id: &str,
+    path: &str,
+    file_size: u64,
+    part_number: u16,
+) -> DynamicResult<(u16, String)> {
+    let start = (part_number as u64 - 1) * PART_SIZE;
+    let length = PART_SIZE.min(file_size - start);
+
+    let mut buffer = vec![0; length as usize];
+    let mut file = File::open(path).await?;
+    file.seek(SeekFrom::Start(start)).await?;
+    file.read_exact(&mut buffer).await?;
+
+    let sha1 = Sha1::from(&buffer).digest().to_string();
+
+    // Parts going up at the same time each need their own url
+    let upload_url = b2.get_upload_part_url(file_id.to_string()).await?;
+    let headers = B2UploadPartHeaders::builder()
+        .authorization(upload_url.authorization_token)
+        .part_number(part_number)
+        .content_length(length)
+        .content_sha1(sha1.clone())
+        .build();
+
+    b2.upload_part(headers, buffer, upload_url.upload_url)
+        .await?;
+
+    tracing::debug!(path, part_number, length, "uploaded part");
+    Ok((part_number, sha1))
+}
+
+/// Sha1 of the whole file, read a chunk at a time so it never has to fit
+/// in memory.
+async fn file_sha1(path: &str) -> DynamicResult<String> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha1::new();
+    let mut buffer = vec![0; HASH_CHUNK];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            return Ok(hasher.digest().to_string());
+        }
+        hasher.update(&buffer[..read]);
     }
-    Ok(results)
+}
+
+async fn find_unfinished(b2: &B2SimpleClient, bucket_id: &str, file_name: &str) -> Option<String> {
+    let query = B2ListUnfinishedLargeFilesQueryParameters::builder()
+        .bucket_id(bucket_id.to_string())
+        .name_prefix(Some(file_name.to_string()))
+        .build();
+
+    // Keys without the list capability just never resume
+    match b2.list_unfinished_large_files(query).await {
+        Ok(response) => response
+            .files
+            .into_iter()
+            .find(|file| file.file_name == file_name)
+            .map(|file| file.file_id),
+        Err(err) => {
+            tracing::debug!(file_name, "failed to list unfinished large files: {}", err);
+            None
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListPartsResponse {
+    parts: Vec<UploadedPart>,
+    next_part_number: Option<u16>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UploadedPart {
+    part_number: u16,
+    content_sha1: String,
+}
+
+/// Sha1s of the parts already uploaded, by part number. The client's own
+/// list_parts can't parse the response, so this calls the api directly.
+async fn list_parts(b2: &B2SimpleClient, file_id: &str) -> DynamicResult<BTreeMap<u16, String>> {
+    let auth = b2.auth_data();
+    let url = format!(
+        "{}/b2api/v3/b2_list_parts",
+        auth.api_info.storage_api.api_url
+    );
+
+    let client = reqwest::Client::new();
+    let mut sha1s = BTreeMap::new();
+    let mut start_part_number = Some(1);
+
+    while let Some(start) = start_part_number {
+        let response = client
+            .get(&url)
+            .header("Authorization", &auth.authorization_token)
+            .query(&[
+                ("fileId", file_id.to_string()),
+                ("startPartNumber", start.to_string()),
+                ("maxPartCount", "1000".to_string()),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<ListPartsResponse>()
+            .await?;
+
+        sha1s.extend(
+            response
+                .parts
+                .into_iter()
+                .map(|part| (part.part_number, part.content_sha1)),
+        );
+        start_part_number = response.next_part_number;
+    }
+
+    Ok(sha1s)
 }
@@ -1,56 +1,461 @@
-use rand::distributions::Alphanumeric;
-use rand::Rng;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::process::Stdio;
 use std::time::Instant;
 
-use ytd_rs::{Arg, YoutubeDL};
+use serde::Deserialize;
+use tokio::process::Command;
+use twilight_model::id::marker::UserMarker;
+use twilight_model::id::Id;
+use url::Url;
 
+use crate::cookies::{auth_args, cookie_file};
+use crate::direct::{download_direct, probe_direct};
+use crate::env::Config;
 use crate::errors::MieError;
+use crate::metrics::YtDlpProcess;
+use crate::proxy::{http_client, is_blocked, network_args, proxy_chain};
+use crate::retry::is_transient_output;
+use crate::upload::UploadFile;
+use crate::workspace::Workspace;
+use crate::AppContext;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaKind {
+    Video,
+    Image,
+    Other,
+}
+
+impl MediaKind {
+    pub fn from_path(path: &Path) -> Self {
+        let extension = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+
+        match extension.as_str() {
+            "mp4" | "webm" | "mov" | "mkv" | "m4v" => MediaKind::Video,
+            "jpg" | "jpeg" | "png" | "webp" | "gif" => MediaKind::Image,
+            _ => MediaKind::Other,
+        }
+    }
+}
+
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "mp4" => "video/mp4",
+        "m4v" => "video/x-m4v",
+        "webm" => "video/webm",
+        "mov" => "video/quicktime",
+        "mkv" => "video/x-matroska",
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "webp" => "image/webp",
+        "gif" => "image/gif",
+        _ => "application/octet-stream",
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quality {
+    Best,
+    // Used when the best quality goes over the size limit
+    Low,
+}
+
+impl Quality {
+    pub fn format(&self) -> &'static str {
+        match self {
+            Quality::Best => "bestvideo[ext=mp4]+bestaudio[ext=m4a]/best[ext=mp4]/best",
+            Quality::Low => {
+                "bestvideo[height<=480][ext=mp4]+bestaudio[ext=m4a]/best[height<=480][ext=mp4]/best[height<=480]/worst"
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DownloadedFile {
+    pub path: String,
+    pub file_name: String,
+    pub kind: MediaKind,
+}
 
 #[derive(Debug)]
 pub struct DownloadedVideo {
     pub og_url: String,
+    // Primary file, the first video if there is one
     pub path: String,
+    // Holds on to the job directory, removed once this is dropped
+    pub workspace: Workspace,
+    pub files: Vec<DownloadedFile>,
     pub download_time: u128,
     pub downloaded_file_name: String,
+    // Post title if the site had one
+    pub title: Option<String>,
+    // Who posted it and where to find them, when the site says
+    pub author: Option<String>,
+    pub author_url: Option<String>,
 }
 
-pub async fn download_video(video_url: &String) -> Result<DownloadedVideo, MieError> {
-    let download_name: String = rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(7)
-        .map(char::from)
-        .collect();
+impl DownloadedVideo {
+    pub fn is_gallery(&self) -> bool {
+        self.files.len() > 1 || self.files.iter().all(|file| file.kind != MediaKind::Video)
+    }
+
+    pub fn primary_is_video(&self) -> bool {
+        MediaKind::from_path(Path::new(&self.path)) == MediaKind::Video
+    }
 
-    tracing::info!(video_url, download_name, "Downloading");
+    /// Bytes on disk across every file.
+    pub fn size(&self) -> u64 {
+        self.files
+            .iter()
+            .filter_map(|file| std::fs::metadata(&file.path).ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    }
+
+    /// Every downloaded file, ready to be uploaded.
+    pub fn uploads(&self, uploader_id: Id<UserMarker>) -> Vec<UploadFile> {
+        self.files
+            .iter()
+            .map(|file| self.upload(&file.path, uploader_id))
+            .collect()
+    }
+
+    /// A file made from this download, the originals or anything derived
+    /// from them like crops, tagged with where it came from.
+    pub fn upload(&self, path: &str, uploader_id: Id<UserMarker>) -> UploadFile {
+        let file_name = Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        UploadFile {
+            path: path.to_string(),
+            content_type: Some(content_type(Path::new(path)).to_string()),
+            download_name: self.download_name(&file_name),
+            info: HashMap::from([
+                ("source-url".to_string(), self.og_url.clone()),
+                ("uploader-id".to_string(), uploader_id.to_string()),
+                ("job-id".to_string(), self.downloaded_file_name.clone()),
+            ]),
+        }
+    }
+
+    /// Name to save the file as, the title with whatever the file name adds
+    /// after the job id, e.g. `_2.mp4` or `_cropped.mp4`.
+    fn download_name(&self, file_name: &str) -> Option<String> {
+        let title = self
+            .title
+            .as_ref()?
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || " -_()[],".contains(*c))
+            .take(80)
+            .collect::<String>();
+
+        let title = title.trim();
+        if title.is_empty() {
+            return None;
+        }
+
+        let suffix = file_name
+            .strip_prefix(&self.downloaded_file_name)
+            .unwrap_or(file_name);
+        Some(format!("{}{}", title, suffix))
+    }
+}
+
+pub async fn download_video(
+    ctx: &AppContext,
+    video_url: &String,
+    quality: Quality,
+    estimated_size: Option<u64>,
+) -> Result<DownloadedVideo, MieError> {
+    let config = &ctx.config;
+
+    // Held until the download finishes, after that the file shows up in
+    // the disk usage on its own
+    let _reservation = ctx.disk.reserve(config, estimated_size).await?;
+    let workspace = Workspace::create(&config.work_dir).await?;
+    let download_name = workspace.id.clone();
+    let dir = workspace.dir();
+
+    tracing::info!(video_url, download_name, ?quality, "Downloading");
     let process_start = Instant::now();
-    let file_name = "/tmp/mie/".to_string() + &download_name.to_string() + ".mp4";
 
-    let args = vec![
-        Arg::new_with_arg(
-            "-f",
-            "bestvideo[ext=mp4]+bestaudio[ext=m4a]/best[ext=mp4]/best",
-        ),
-        Arg::new_with_arg("-o", &file_name),
-    ];
+    // Raw file links don't need yt-dlp at all
+    if let Some(files) = try_direct(config, video_url, &workspace).await? {
+        let download_time = process_start.elapsed().as_millis();
+        tracing::info!(video_url, "Direct download took {}ms", download_time);
+
+        return Ok(DownloadedVideo {
+            path: files[0].path.clone(),
+            workspace,
+            files,
+            og_url: video_url.to_string(),
+            download_time,
+            downloaded_file_name: download_name,
+            title: direct_title(video_url),
+            author: None,
+            author_url: None,
+        });
+    }
+
+    // yt-dlp writes the cookie jar back when it exits, give it a copy so
+    // jobs running at the same time don't clobber the original
+    let cookies = match cookie_file(config, video_url) {
+        Some(original) => {
+            let copy = PathBuf::from(workspace.path("cookies.txt"));
+            tokio::fs::copy(&original, &copy).await.ok().map(|_| copy)
+        }
+        None => None,
+    };
+
+    // Single videos keep the plain name, playlist entries (multi video
+    // tweets etc.) get their index appended
+    let output_template = format!("{}/{}%(playlist_index&_{{}}|)s.%(ext)s", dir, download_name);
+
+    let mut proxy = None;
+    let mut ytdl_error = None;
+    let mut info = PostInfo::default();
 
-    let path = PathBuf::from("/tmp/mie");
-    let ytd = YoutubeDL::new(&path, args, video_url.as_str()).map_err(MieError::YtDlError)?;
-    let _ = ytd.download().map_err(MieError::YtDlError);
+    // Go through the fallback proxies only while the site keeps blocking us
+    for next_proxy in proxy_chain(config, video_url) {
+        proxy = next_proxy;
+
+        let mut command = Command::new("yt-dlp");
+        command
+            .args(["-f", quality.format()])
+            .args(["--merge-output-format", "mp4"])
+            // Backup for when the probe underestimated the size
+            .args(["--max-filesize", &config.max_download_size.to_string()])
+            .args(["-o", &output_template])
+            .args([
+                "--print",
+                "after_move:%(.{title,uploader,uploader_url,channel_url})j",
+                "--no-simulate",
+            ]);
+
+        let auth = auth_args(config, cookies.as_deref());
+        for (flag, value) in auth
+            .into_iter()
+            .chain(network_args(config, proxy.as_deref()))
+        {
+            command.arg(flag).args(value);
+        }
+
+        let _process = YtDlpProcess::start();
+        let output = command
+            .arg(video_url)
+            .current_dir(&dir)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .output()
+            .await
+            .map_err(|err| MieError::YtDlError(err.to_string()))?;
+
+        // One line per file, multi video posts repeat the same info
+        info = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .find_map(|line| serde_json::from_str(line.trim()).ok())
+            .unwrap_or_default();
+
+        if output.status.success() {
+            break;
+        }
+
+        let err = String::from_utf8_lossy(&output.stderr).to_string();
+        if !is_blocked(&err) {
+            tracing::debug!(video_url, "yt-dlp failed: {}", err);
+            ytdl_error = Some(err);
+            break;
+        }
+        tracing::warn!(
+            video_url,
+            ?proxy,
+            "yt-dlp was blocked, trying the next proxy"
+        );
+    }
+
+    let mut files = collect_files(&dir).await;
+
+    // yt-dlp doesn't handle image only posts, see if gallery-dl can
+    if files.is_empty() {
+        // gallery-dl has no netrc location flag, only pass it the cookies
+        let mut args = network_args(config, proxy.as_deref());
+        if let Some(cookies) = &cookies {
+            args.push((
+                "--cookies".to_string(),
+                Some(cookies.to_string_lossy().to_string()),
+            ));
+        }
+        download_gallery(video_url, &dir, &download_name, args).await;
+        files = collect_files(&dir).await;
+    }
 
     let download_time = process_start.elapsed().as_millis();
-    tracing::info!(video_url, "Downloading took {}ms", download_time);
+    tracing::info!(
+        video_url,
+        files = files.len(),
+        "Downloading took {}ms",
+        download_time
+    );
+
+    let primary = files
+        .iter()
+        .find(|file| file.kind == MediaKind::Video)
+        .or(files.first())
+        .map(|file| file.path.clone())
+        .unwrap_or_default();
 
     let downloaded_video = DownloadedVideo {
-        path: file_name,
+        path: primary,
+        workspace,
+        files,
         og_url: video_url.to_string(),
         download_time,
         downloaded_file_name: download_name,
+        title: info.title,
+        author: info.uploader,
+        author_url: info.uploader_url.or(info.channel_url),
     };
 
-    if !Path::new(&downloaded_video.path).is_file() {
-        return Err(MieError::VideoDownloadFailed(downloaded_video));
+    if downloaded_video.files.is_empty() {
+        // Network trouble is worth retrying, unlike a url with nothing on it
+        if let Some(err) = ytdl_error.filter(|err| is_transient_output(err)) {
+            return Err(MieError::YtDlError(err));
+        }
+        return Err(MieError::VideoDownloadFailed(Box::new(downloaded_video)));
     }
 
     Ok(downloaded_video)
 }
+
+// What yt-dlp knows about the post, missing fields are left out
+#[derive(Debug, Default, Deserialize)]
+struct PostInfo {
+    title: Option<String>,
+    uploader: Option<String>,
+    uploader_url: Option<String>,
+    channel_url: Option<String>,
+}
+
+// Direct links have no title, the file name is the closest thing to one
+fn direct_title(video_url: &str) -> Option<String> {
+    let url = Url::parse(video_url).ok()?;
+    let file_name = url.path_segments()?.next_back()?;
+    let file_name = urlencoding::decode(file_name).ok()?;
+
+    Path::new(file_name.as_ref())
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+}
+
+async fn try_direct(
+    config: &Config,
+    video_url: &str,
+    workspace: &Workspace,
+) -> Result<Option<Vec<DownloadedFile>>, MieError> {
+    let Ok(url) = Url::parse(video_url) else {
+        return Ok(None);
+    };
+
+    // Direct links only go through the primary proxy, if they get blocked
+    // yt-dlp tries the rest
+    let proxy = proxy_chain(config, video_url).swap_remove(0);
+    let client = http_client(config, proxy.as_deref())?;
+    let Some(direct) = probe_direct(&client, &url).await else {
+        return Ok(None);
+    };
+
+    if let Some(size) = direct.size.filter(|size| *size > config.max_download_size) {
+        return Err(MieError::FileTooLarge(size));
+    }
+
+    let file_name = format!("{}.{}", workspace.id, direct.extension);
+    let path = workspace.path(&file_name);
+
+    match download_direct(&client, &url, &path, config.max_download_size).await {
+        Ok(_) => Ok(Some(vec![DownloadedFile {
+            kind: MediaKind::from_path(Path::new(&path)),
+            path,
+            file_name,
+        }])),
+        Err(MieError::DirectDownloadFailed(err)) => {
+            tracing::warn!(video_url, "direct download failed, trying yt-dlp: {}", err);
+            let _ = tokio::fs::remove_file(&path).await;
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+async fn download_gallery(
+    video_url: &str,
+    dir: &str,
+    download_name: &str,
+    args: Vec<(String, Option<String>)>,
+) {
+    let mut command = Command::new("gallery-dl");
+    for (flag, value) in args {
+        command.arg(flag).args(value);
+    }
+
+    let output = command
+        .args(["--directory", dir])
+        .args([
+            "--filename",
+            &format!("{}_{{num:>03}}.{{extension}}", download_name),
+        ])
+        .arg(video_url)
+        .kill_on_drop(true)
+        .output()
+        .await;
+
+    match output {
+        Ok(output) if !output.status.success() => {
+            tracing::debug!(
+                video_url,
+                "gallery-dl failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        Err(err) => tracing::debug!(video_url, "could not run gallery-dl: {}", err),
+        _ => {}
+    }
+}
+
+async fn collect_files(dir: &str) -> Vec<DownloadedFile> {
+    let mut files = vec![];
+    let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+        return files;
+    };
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        let kind = MediaKind::from_path(&path);
+
+        // Skip partial downloads and any metadata files
+        if !path.is_file() || kind == MediaKind::Other {
+            continue;
+        }
+
+        files.push(DownloadedFile {
+            path: path.to_string_lossy().to_string(),
+            file_name: entry.file_name().to_string_lossy().to_string(),
+            kind,
+        });
+    }
+
+    files.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+    files
+}
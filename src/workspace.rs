@@ -0,0 +1,95 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+
+use crate::errors::MieError;
+
+const ID_LEN: usize = 7;
+// Every workspace directory starts with this, the sweep leaves anything else
+const DIR_PREFIX: &str = "mie-";
+
+/// A directory owned by a single job, removed along with everything in it
+/// once the workspace is dropped. Failed, cancelled and finished jobs all
+/// clean up the same way.
+#[derive(Debug)]
+pub struct Workspace {
+    pub id: String,
+    dir: PathBuf,
+}
+
+impl Workspace {
+    pub async fn create(root: &str) -> Result<Self, MieError> {
+        tokio::fs::create_dir_all(root)
+            .await
+            .map_err(|err| MieError::WorkspaceError(err.to_string()))?;
+
+        loop {
+            let id: String = rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(ID_LEN)
+                .map(char::from)
+                .collect();
+            let dir = Path::new(root).join(format!("{}{}", DIR_PREFIX, id));
+
+            // create_dir fails if it already exists, so two jobs never share one
+            match tokio::fs::create_dir(&dir).await {
+                Ok(()) => return Ok(Workspace { id, dir }),
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(MieError::WorkspaceError(err.to_string())),
+            }
+        }
+    }
+
+    pub fn dir(&self) -> String {
+        self.dir.to_string_lossy().to_string()
+    }
+
+    pub fn path(&self, file_name: &str) -> String {
+        self.dir.join(file_name).to_string_lossy().to_string()
+    }
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        let dir = std::mem::take(&mut self.dir);
+        // Big downloads take a while to delete, keep that off the runtime
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn_blocking(move || remove_workspace(&dir));
+            }
+            Err(_) => remove_workspace(&dir),
+        }
+    }
+}
+
+fn remove_workspace(dir: &Path) {
+    if let Err(err) = fs::remove_dir_all(dir) {
+        if err.kind() != ErrorKind::NotFound {
+            tracing::warn!(?dir, "failed to clean up workspace: {}", err);
+        }
+    }
+}
+
+/// Removes workspaces left behind in the root, only meant to be called on
+/// startup before any job has had a chance to create one. Anything without
+/// the workspace prefix is left alone in case the root is shared.
+pub fn sweep_stale(root: &str) {
+    let Ok(entries) = fs::read_dir(root) else {
+        return;
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if !path.is_dir() || !entry.file_name().to_string_lossy().starts_with(DIR_PREFIX) {
+            continue;
+        }
+
+        match fs::remove_dir_all(&path) {
+            Ok(()) => tracing::info!(?path, "removed stale workspace"),
+            Err(err) => tracing::warn!(?path, "failed to remove stale workspace: {}", err),
+        }
+    }
+}